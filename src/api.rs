@@ -2,18 +2,39 @@ use axum::Router;
 
 use crate::Context;
 
+pub mod access_log;
+mod cache;
+pub mod compression;
 mod default_route;
 mod drive;
+mod files;
+mod health;
+mod mastodon;
+pub mod metrics;
+mod rate_limit;
+pub mod route_table;
+mod streaming;
 
 pub fn route(ctx: &Context,app: Router)->Router{
 	let app=drive::route(ctx,app);
+	let app=mastodon::route(ctx,app);
+	let app=files::route(ctx,app);
+	let app=cache::route(ctx,app);
+	let app=metrics::route(ctx,app);
+	let app=health::route(ctx,app);
 	let arg_tup0=ctx.clone();
-	let app=app.route("/streaming",axum::routing::get(move|ws,req|default_route::streaming(arg_tup0.clone(),ws,req)));
+	let app=app.route("/streaming",axum::routing::get(move|addr,headers,ws,req|streaming::streaming(arg_tup0.clone(),addr,headers,ws,req)));
 	let arg_tup0=ctx.clone();
-	let app=app.route("/*path",axum::routing::post(move|body|default_route::post(arg_tup0.clone(),body)));
+	let app=app.route("/*path",axum::routing::any(move|addr,body|default_route::proxy(arg_tup0.clone(),addr,body)));
 	let arg_tup0=ctx.clone();
-	let app=app.route("/*path",axum::routing::get(move|body|default_route::get(arg_tup0.clone(),body)));
+	let app=app.route("/",axum::routing::any(move|addr,body|default_route::proxy(arg_tup0.clone(),addr,body)));
 	let arg_tup0=ctx.clone();
-	let app=app.route("/",axum::routing::get(move|body|default_route::get(arg_tup0.clone(),body)));
-	app
+	let app=app.layer(axum::middleware::from_fn(move|addr,req,next|route_table::dispatch(arg_tup0.clone(),addr,req,next)));
+	//経路表より先に制限する
+	let arg_tup0=ctx.clone();
+	let app=app.layer(axum::middleware::from_fn(move|addr,req,next|rate_limit::limit(arg_tup0.clone(),addr,req,next)));
+	let arg_tup0=ctx.clone();
+	let app=app.layer(axum::middleware::from_fn(move|req,next|compression::compress(arg_tup0.clone(),req,next)));
+	let arg_tup0=ctx.clone();
+	app.layer(axum::middleware::from_fn(move|addr,req,next|access_log::log(arg_tup0.clone(),addr,req,next)))
 }
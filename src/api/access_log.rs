@@ -0,0 +1,100 @@
+use std::{net::{IpAddr, SocketAddr}, time::Instant};
+
+use axum::{body::HttpBody, http::{header, HeaderValue, Method, StatusCode}, middleware::Next};
+use futures::StreamExt;
+use tracing::Instrument;
+
+use crate::Context;
+
+pub const X_REQUEST_ID:&str="x-request-id";
+
+fn is_valid_request_id(id:&str)->bool{
+	!id.is_empty()&&id.len()<=128&&id.chars().all(|c|c.is_ascii_alphanumeric()||c=='-'||c=='_'||c=='.')
+}
+/**
+ * 処理中のリクエストのアクセスログにユーザーIDを記録する
+ */
+pub fn record_user(user_id:&str){
+	tracing::Span::current().record("user_id",user_id);
+}
+/**
+ * 本文を送り終わった(または中断された)時点でdropされてログを出力する
+ */
+struct AccessLog{
+	span:tracing::Span,
+	method:Method,
+	path:String,
+	status:u16,
+	client_ip:IpAddr,
+	user_agent:Option<String>,
+	start:Instant,
+	ttfb_ms:u128,
+	bytes:u64,
+}
+impl Drop for AccessLog{
+	fn drop(&mut self) {
+		let _enter=self.span.enter();
+		tracing::info!(
+			target:"access",
+			method=%self.method,
+			path=%self.path,
+			status=self.status,
+			client_ip=%self.client_ip,
+			user_agent=self.user_agent.as_deref(),
+			ttfb_ms=self.ttfb_ms as u64,
+			latency_ms=self.start.elapsed().as_millis() as u64,
+			bytes=self.bytes,
+			"request completed"
+		);
+	}
+}
+pub async fn log(
+	ctx:Context,
+	axum::extract::ConnectInfo(addr):axum::extract::ConnectInfo<SocketAddr>,
+	mut request: axum::extract::Request,
+	next:Next,
+)->axum::response::Response{
+	let start=Instant::now();
+	//信用できるプロキシから来た場合のみ受け取ったIDを引き継ぐ
+	let trusted=crate::client_ip::is_trusted_proxy(&ctx.config.load(),&addr.ip().to_canonical());
+	let request_id=request.headers().get(X_REQUEST_ID).and_then(|v|v.to_str().ok()).filter(|v|trusted&&is_valid_request_id(v)).map(|v|v.to_owned());
+	let request_id=request_id.unwrap_or_else(|| uuid::Uuid::new_v4().to_string());
+	let request_id_value=HeaderValue::from_str(&request_id).unwrap_or(HeaderValue::from_static("-"));
+	request.headers_mut().insert(X_REQUEST_ID,request_id_value.clone());
+	let span=tracing::info_span!("request",request_id=%request_id,user_id=tracing::field::Empty);
+	let method=request.method().clone();
+	//クエリにはトークンが含まれることがあるのでパスだけ記録する
+	let path=request.uri().path().to_owned();
+	let client_ip=crate::client_ip::client_ip(&ctx.config.load(),&addr,request.headers());
+	let user_agent=request.headers().get(header::USER_AGENT).and_then(|v|v.to_str().ok()).map(|v|v.to_owned());
+	let res=next.run(request).instrument(span.clone()).await;
+	let (mut parts,body)=res.into_parts();
+	parts.headers.insert(X_REQUEST_ID,request_id_value);
+	//ストリームに包むと長さが分からなくなるので先にContent-Lengthを付けておく
+	let has_body=method!=Method::HEAD&&!parts.status.is_informational()&&!matches!(parts.status,StatusCode::NO_CONTENT|StatusCode::NOT_MODIFIED);
+	if has_body&&!parts.headers.contains_key(header::CONTENT_LENGTH){
+		if let Some(len)=body.size_hint().exact(){
+			parts.headers.insert(header::CONTENT_LENGTH,len.into());
+		}
+	}
+	let mut log=AccessLog{
+		span,
+		method,
+		path,
+		status:parts.status.as_u16(),
+		client_ip,
+		user_agent,
+		start,
+		ttfb_ms:start.elapsed().as_millis(),
+		bytes:0,
+	};
+	let body=axum::body::Body::from_stream(body.into_data_stream().map(move|chunk|{
+		//フィールドのコピーではなく全体を捕捉して、本文の送信後にDropさせる
+		let log=&mut log;
+		if let Ok(chunk)=&chunk{
+			log.bytes+=chunk.len() as u64;
+		}
+		chunk
+	}));
+	axum::response::Response::from_parts(parts,body)
+}
@@ -0,0 +1,58 @@
+use axum::{http::StatusCode, response::IntoResponse, Router};
+use serde::Deserialize;
+use sha2::{Digest, Sha256};
+
+use crate::Context;
+
+#[derive(Debug, Default, Deserialize)]
+pub struct PurgeParams{
+	//省略時は全て削除
+	paths:Option<Vec<String>>,
+}
+pub fn route(ctx: &Context,app: Router)->Router{
+	if ctx.response_cache.purge_token().is_none(){
+		return app;
+	}
+	let ctx0=ctx.clone();
+	app.route("/_cache/purge",axum::routing::post(move|headers,body|purge(ctx0.clone(),headers,body)))
+}
+/**
+ * 一致するまでの時間から推測されないように、ハッシュを全バイト比較する
+ */
+fn token_eq(a:&str,b:&str)->bool{
+	let a=Sha256::digest(a.as_bytes());
+	let b=Sha256::digest(b.as_bytes());
+	a.iter().zip(b.iter()).fold(0u8,|acc,(x,y)|acc|(x^y))==0
+}
+pub async fn purge(
+	ctx:Context,
+	headers:axum::http::HeaderMap,
+	body:axum::body::Bytes,
+)->axum::response::Response{
+	let token=headers.get(axum::http::header::AUTHORIZATION).and_then(|v|v.to_str().ok()).and_then(|v|v.strip_prefix("Bearer "));
+	let authorized=match (token,ctx.response_cache.purge_token()){
+		(Some(token),Some(purge_token))=>token_eq(token,purge_token),
+		_=>false,
+	};
+	if !authorized{
+		return StatusCode::FORBIDDEN.into_response();
+	}
+	let params=if body.is_empty(){
+		PurgeParams::default()
+	}else{
+		match serde_json::from_slice::<PurgeParams>(&body){
+			Ok(v)=>v,
+			Err(e)=>{
+				tracing::error!("{:?}",e);
+				return StatusCode::BAD_REQUEST.into_response();
+			}
+		}
+	};
+	match ctx.response_cache.purge(params.paths.as_deref()).await{
+		Ok(_)=>StatusCode::NO_CONTENT.into_response(),
+		Err(e)=>{
+			tracing::error!("{:?}",e);
+			StatusCode::INTERNAL_SERVER_ERROR.into_response()
+		}
+	}
+}
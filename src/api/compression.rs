@@ -0,0 +1,166 @@
+use async_compression::{tokio::bufread::{BrotliEncoder, GzipEncoder, ZstdEncoder}, Level};
+use axum::{http::{header, HeaderMap, HeaderValue, Method, StatusCode}, middleware::Next};
+use futures::TryStreamExt;
+use serde::{Deserialize, Serialize};
+use tokio_util::io::{ReaderStream, StreamReader};
+
+use crate::Context;
+
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub struct CompressionConfig{
+	//サーバー側の優先順("zstd","br","gzip")
+	encodings:Vec<String>,
+	//Content-Lengthがこれより小さい応答は圧縮しない
+	min_size:u64,
+	//圧縮するContent-Type(前方一致)
+	content_types:Vec<String>,
+}
+impl Default for CompressionConfig{
+	fn default() -> Self {
+		Self{
+			encodings:vec!["zstd".to_owned(),"br".to_owned(),"gzip".to_owned()],
+			min_size:1024,
+			content_types:vec![
+				"application/json".to_owned(),
+				"application/activity+json".to_owned(),
+				"application/ld+json".to_owned(),
+				"application/javascript".to_owned(),
+				"application/manifest+json".to_owned(),
+				"text/html".to_owned(),
+				"text/javascript".to_owned(),
+				"text/css".to_owned(),
+			],
+		}
+	}
+}
+impl CompressionConfig{
+	pub fn validate(&self,errors:&mut Vec<String>){
+		for name in self.encodings.iter(){
+			if Encoding::from_name(name).is_none(){
+				errors.push(format!("compression.encodings: unsupported encoding {:?}",name));
+			}
+		}
+	}
+}
+#[derive(Clone, Copy,Debug)]
+enum Encoding{
+	Zstd,
+	Brotli,
+	Gzip,
+}
+impl Encoding{
+	fn from_name(name:&str)->Option<Self>{
+		match name{
+			"zstd"=>Some(Self::Zstd),
+			"br"=>Some(Self::Brotli),
+			"gzip"=>Some(Self::Gzip),
+			_=>None,
+		}
+	}
+	fn name(&self)->&'static str{
+		match self{
+			Self::Zstd=>"zstd",
+			Self::Brotli=>"br",
+			Self::Gzip=>"gzip",
+		}
+	}
+}
+/**
+ * Accept-Encodingのq値が0でないものからサーバー側の優先順で選ぶ
+ */
+fn negotiate(config:&CompressionConfig,headers:&HeaderMap)->Option<Encoding>{
+	let accepted:Vec<(String,f32)>=headers.get_all(header::ACCEPT_ENCODING).iter().filter_map(|v|v.to_str().ok()).flat_map(|v|v.split(',')).map(|v|{
+		let mut parts=v.split(';');
+		let name=parts.next().unwrap_or_default().trim().to_lowercase();
+		let q=parts.filter_map(|p|p.trim().strip_prefix("q=")).filter_map(|q|q.parse::<f32>().ok()).next().unwrap_or(1.0);
+		(name,q)
+	}).collect();
+	let wildcard=accepted.iter().find(|(name,_)|name=="*").map(|(_,q)|*q);
+	config.encodings.iter().find(|name|{
+		match accepted.iter().find(|(n,_)|n==*name){
+			Some((_,q))=>*q>0.0,
+			None=>wildcard.map(|q|q>0.0).unwrap_or(false),
+		}
+	}).and_then(|name|Encoding::from_name(name))
+}
+fn is_compressible(config:&CompressionConfig,status:StatusCode,headers:&HeaderMap)->bool{
+	if status.is_informational()||matches!(status,StatusCode::NO_CONTENT|StatusCode::PARTIAL_CONTENT|StatusCode::NOT_MODIFIED){
+		return false;
+	}
+	if headers.contains_key(header::CONTENT_ENCODING)||headers.contains_key(header::CONTENT_RANGE){
+		return false;
+	}
+	let no_transform=headers.get_all(header::CACHE_CONTROL).iter().filter_map(|v|v.to_str().ok()).any(|v|v.to_lowercase().contains("no-transform"));
+	if no_transform{
+		return false;
+	}
+	let content_type=match headers.get(header::CONTENT_TYPE).and_then(|v|v.to_str().ok()){
+		Some(v)=>v.to_lowercase(),
+		None=>return false,
+	};
+	if !config.content_types.iter().any(|t|content_type.starts_with(t.as_str())){
+		return false;
+	}
+	//長さが分からないものは圧縮する
+	match headers.get(header::CONTENT_LENGTH).and_then(|v|v.to_str().ok()).and_then(|v|v.parse::<u64>().ok()){
+		Some(len)=>len>=config.min_size,
+		None=>true,
+	}
+}
+/**
+ * ドライブのファイルは圧縮しない
+ */
+fn is_drive_file(ctx:&Context,path:&str)->bool{
+	let base_path=match reqwest::Url::parse(&ctx.config.load().public_base_url){
+		Ok(url)=>url.path().trim_end_matches('/').to_owned(),
+		Err(_)=>return false,
+	};
+	path.starts_with(&format!("{}/{}/",base_path,ctx.config.load().prefix))
+}
+pub async fn compress(
+	ctx:Context,
+	request: axum::extract::Request,
+	next:Next,
+)->axum::response::Response{
+	let config_file=ctx.config.load();
+	let config=match config_file.compression.as_ref(){
+		Some(config)=>config,
+		None=>return next.run(request).await,
+	};
+	let encoding=if request.method()==Method::HEAD||is_drive_file(&ctx,request.uri().path()){
+		None
+	}else{
+		negotiate(config,request.headers())
+	};
+	let res=next.run(request).await;
+	if !is_compressible(config,res.status(),res.headers()){
+		return res;
+	}
+	let (mut parts,body)=res.into_parts();
+	let has_vary=parts.headers.get_all(header::VARY).iter().filter_map(|v|v.to_str().ok()).any(|v|v.to_lowercase().contains("accept-encoding"));
+	if !has_vary{
+		parts.headers.append(header::VARY,HeaderValue::from_static("accept-encoding"));
+	}
+	let encoding=match encoding{
+		Some(encoding)=>encoding,
+		None=>return axum::response::Response::from_parts(parts,body),
+	};
+	parts.headers.remove(header::CONTENT_LENGTH);
+	parts.headers.insert(header::CONTENT_ENCODING,HeaderValue::from_static(encoding.name()));
+	//圧縮後は同じバイト列ではなくなるので弱いETagにする
+	if let Some(etag)=parts.headers.get(header::ETAG).and_then(|v|v.to_str().ok()){
+		if !etag.starts_with("W/"){
+			if let Ok(v)=HeaderValue::from_str(&format!("W/{}",etag)){
+				parts.headers.insert(header::ETAG,v);
+			}
+		}
+	}
+	let reader=StreamReader::new(body.into_data_stream().map_err(|e|std::io::Error::new(std::io::ErrorKind::Other,e)));
+	let body=match encoding{
+		Encoding::Zstd=>axum::body::Body::from_stream(ReaderStream::new(ZstdEncoder::new(reader))),
+		//brotliの既定値(11)は逐次圧縮には重すぎる
+		Encoding::Brotli=>axum::body::Body::from_stream(ReaderStream::new(BrotliEncoder::with_quality(reader,Level::Precise(4)))),
+		Encoding::Gzip=>axum::body::Body::from_stream(ReaderStream::new(GzipEncoder::new(reader))),
+	};
+	axum::response::Response::from_parts(parts,body)
+}
@@ -1,259 +1,147 @@
-use std::sync::{atomic::AtomicI64, Arc};
+use std::net::SocketAddr;
 
-use axum::{extract::ws::WebSocketUpgrade, http::StatusCode, response::IntoResponse};
-use futures::{stream::{SplitSink, SplitStream}, SinkExt, StreamExt, TryStreamExt};
-use reqwest::{header::USER_AGENT, Client};
-use serde::Deserialize;
-use tokio::io::AsyncReadExt;
-use tokio_util::io::StreamReader;
+use axum::{body::HttpBody, http::{HeaderMap, StatusCode}, response::IntoResponse};
+use futures::StreamExt;
+use reqwest::header::{ACCEPT_ENCODING, CONNECTION, CONTENT_LENGTH, TRANSFER_ENCODING};
 
-use crate::Context;
+use crate::{service::{response_cache::CachedResponse, upstream::UpstreamGuard}, Context};
 
-pub async fn post(
-	ctx:Context,
-	request: axum::extract::Request,
-)->axum::response::Response{
-	let path=request.uri().path();
-	let mut url=format!("{}{}",ctx.config.backend,path);
-	if let Some(query)=request.uri().query(){
-		url+="?";
-		url+=query;
-	}
-	let headers=request.headers();
-	println!("[{}] \"POST {}\" \"{:?}\"",chrono::Utc::now().format("%+"),url,headers.get(USER_AGENT).map(|s|s.to_str().map(|s|s.replace("\"","'"))));
-	let builder=ctx.client.post(url);
-	let builder=builder.headers(headers.clone());
-	let stream=request.into_body().into_data_stream();
-	let body_with_io_error = stream.map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err));
-	let mut body_reader = StreamReader::new(body_with_io_error);
-	let mut buf=vec![];
-	if let Err(e)=body_reader.read_to_end(&mut buf).await{
-		eprintln!("{}:{} {:?}",file!(),line!(),e);
-		return (StatusCode::INTERNAL_SERVER_ERROR).into_response();
-	}
-	let builder=builder.body(buf);
-	let res=builder.send().await;
-	match res{
-		Ok(v)=>{
-			let mut header=axum::http::header::HeaderMap::new();
-			for (k,v) in v.headers(){
-				header.append(k,v.clone());
-			}
-			let status=v.status().as_u16();
-			let body=v.bytes().await.map(|b|b.to_vec()).unwrap_or_default();
-			(StatusCode::from_u16(status).unwrap_or(StatusCode::BAD_GATEWAY),header,body).into_response()
-		},
-		Err(e)=>{
-			eprintln!("{:?}",e);
-			StatusCode::BAD_GATEWAY.into_response()
+//プロキシで終端するヘッダ
+const HOP_BY_HOP_HEADERS:[&str;8]=[
+	"connection",
+	"keep-alive",
+	"proxy-authenticate",
+	"proxy-authorization",
+	"te",
+	"trailer",
+	"transfer-encoding",
+	"upgrade",
+];
+fn filter_hop_by_hop(src:&HeaderMap)->HeaderMap{
+	//Connectionヘッダで列挙されたものもhop-by-hop扱い
+	let connection:Vec<String>=src.get_all(CONNECTION).iter().filter_map(|v|v.to_str().ok()).flat_map(|v|v.split(',')).map(|v|v.trim().to_lowercase()).collect();
+	let mut header=HeaderMap::new();
+	for (k,v) in src{
+		if HOP_BY_HOP_HEADERS.contains(&k.as_str())||connection.iter().any(|c|c==k.as_str()){
+			continue;
 		}
+		header.append(k,v.clone());
 	}
+	header
 }
-#[derive(Debug, Deserialize)]
-pub struct StreamingParams{
-	#[serde(rename = "i")]
-	token:Option<String>,
-}
-pub async fn streaming(
-	ctx:Context,
-	ws: WebSocketUpgrade,
-	axum::extract::Query(q):axum::extract::Query<StreamingParams>,
-)->axum::response::Response{
-	ws.on_upgrade(|socket| handle_socket(socket, ctx,q))
+fn backend_response(res:reqwest::Response,upstream:UpstreamGuard)->axum::response::Response{
+	let header=filter_hop_by_hop(res.headers());
+	let status=StatusCode::from_u16(res.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY);
+	//本文を転送し終わるまで接続中として数える
+	let body=axum::body::Body::from_stream(res.bytes_stream().map(move|chunk|{
+		let _=&upstream;
+		chunk
+	}));
+	(status,header,body).into_response()
 }
-async fn handle_socket(
-	socket: axum::extract::ws::WebSocket,
-	ctx:Context,
-	q:StreamingParams,
-) {
-	let (sender, receiver) = socket.split();
-	let backend=ws_backend(ctx.client.clone(),&ctx.config.backend,q.token.as_deref()).await;
-	match backend{
-		Ok(backend)=>{
-			let (backend_sender, backend_receiver) = backend.split();
-			let ping_sync=Arc::new(AtomicI64::new(chrono::Utc::now().timestamp_millis()));
-			let sender_handle = tokio::spawn(ws_write_side(sender, backend_receiver,ctx.clone(),ping_sync.clone()));
-			let reciever_handle = tokio::spawn(ws_read_side(receiver, sender_handle.abort_handle(),backend_sender, ctx.clone(),ping_sync));
-			let _=reciever_handle.await;
-			println!("exit handle_socket");
-		},
+/**
+ * キャッシュできる応答は本文を読み切って保存してから返す
+ */
+async fn cache_response(ctx:&Context,key:String,res:reqwest::Response,upstream:UpstreamGuard)->axum::response::Response{
+	let status=res.status().as_u16();
+	let ttl=match ctx.response_cache.ttl(status,res.headers()){
+		Some(ttl)=>ttl,
+		None=>return backend_response(res,upstream),
+	};
+	let header=filter_hop_by_hop(res.headers());
+	let body=match res.bytes().await{
+		Ok(body)=>body,
 		Err(e)=>{
-			eprintln!("backend ws error {:?}",e);
-		}
-	}
-}
-
-async fn ws_read_side(
-	mut receiver: SplitStream<axum::extract::ws::WebSocket>,
-	sender_handle: tokio::task::AbortHandle,
-	mut backend_sender: SplitSink<reqwest_websocket::WebSocket, reqwest_websocket::Message>,
-	ctx:Context,
-	ping_sync:Arc<AtomicI64>,
-) {
-	//80秒pingが無ければ、接続が切れたと判定して処理を終了する
-	let timeout = std::time::Duration::from_secs(80);
-
-	loop {
-		let result = tokio::time::timeout(timeout, receiver.next()).await;
-		if sender_handle.is_finished(){
-			return;
-		}
-		match result {
-			// A new message has been received
-			Ok(Some(Ok(message))) => match message {
-				axum::extract::ws::Message::Close(_frame) => {
-					println!("close from client");
-					break;
-				}
-				_ => {
-					let message=message.into_text();
-					//println!("WS from client {:?}",message);
-					if let Ok(message)=message{
-						let res = backend_sender.send(reqwest_websocket::Message::Text(message)).await;
-						if let Err(e)=res{
-							eprintln!("WS send to backend error {:?}",e);
-							break;
-						}else{
-							ping_sync.store(chrono::Utc::now().timestamp_millis(), std::sync::atomic::Ordering::Relaxed);
-						}
-					}
-				}
-			},
-			// An error occurred while trying to read a message
-			Ok(Some(Err(e))) => {
-				eprintln!("Read from client MessageError: {:?}", e);
-				break;
-			}
-			Ok(None) => {
-				println!("receive nothing.");
-			}
-			// Timeout occurred
-			Err(e ) => {
-				let last_send=ping_sync.load(std::sync::atomic::Ordering::Relaxed);
-				let now=chrono::Utc::now().timestamp_millis();
-				if now-60*1000 < last_send{
-					continue;
-				}
-				println!("from client timeout! {:?}", e);
-				break;
-			}
+			tracing::error!("{:?}",e);
+			return StatusCode::BAD_GATEWAY.into_response();
 		}
-	}
-	ping_sync.store(0, std::sync::atomic::Ordering::Relaxed);
-	//receive処理を抜けるタイミングで、sender側の処理も終了する
-	sender_handle.abort();
+	};
+	let cached=CachedResponse::new(status,&header,body.to_vec());
+	ctx.response_cache.put(key,cached.clone(),ttl).await;
+	cached.into_response("MISS")
 }
-async fn ws_write_side(
-	mut sender: SplitSink<axum::extract::ws::WebSocket,axum::extract::ws::Message>,
-	mut backend_receiver: SplitStream<reqwest_websocket::WebSocket>,
+pub async fn proxy(
 	ctx:Context,
-	ping_sync:Arc<AtomicI64>,
-) {
-	let timeout = std::time::Duration::from_secs(100);
-	loop {
-		let result = tokio::time::timeout(timeout, backend_receiver.next()).await;
-		match result {
-			// A new message has been received
-			Ok(Some(Ok(message))) => match message {
-				reqwest_websocket::Message::Text(message) => {
-					//println!("WS from backend {:?}",message);
-					let res = sender.send(axum::extract::ws::Message::Text(message)).await;
-					if let Err(e)=res{
-						eprintln!("WS send to client error {:?}",e);
-						break;
-					}else{
-						ping_sync.store(chrono::Utc::now().timestamp_millis(), std::sync::atomic::Ordering::Relaxed);
-					}
-				},
-				_=>{
-					//
-				}
-			},
-			// An error occurred while trying to read a message
-			Ok(Some(Err(e))) => {
-				eprintln!("Read from backend MessageError: {:?}", e);
-				break;
-			}
-			Ok(None) => {
-				println!("receive nothing.");
-			}
-			// Timeout occurred
-			Err(e ) => {
-				let last_send=ping_sync.load(std::sync::atomic::Ordering::Relaxed);
-				let now=chrono::Utc::now().timestamp_millis();
-				if now-60*1000 < last_send{
-					continue;
-				}
-				println!("from backend timeout! {:?}", e);
-				break;
-			}
-		}
-	}
-	println!("exit ws_write_side");
+	connect_info:axum::extract::ConnectInfo<SocketAddr>,
+	request: axum::extract::Request,
+)->axum::response::Response{
+	proxy_to(ctx,connect_info,request,None).await
 }
-pub async fn get(
+/**
+ * backendを指定した場合はその名前のバックエンドだけに転送する
+ */
+pub async fn proxy_to(
 	ctx:Context,
+	axum::extract::ConnectInfo(addr):axum::extract::ConnectInfo<SocketAddr>,
 	request: axum::extract::Request,
+	backend:Option<&str>,
 )->axum::response::Response{
-	let path=request.uri().path();
-	let mut url=format!("{}{}",ctx.config.backend,path);
+	let mut path=request.uri().path().to_owned();
 	if let Some(query)=request.uri().query(){
-		url+="?";
-		url+=query;
+		path+="?";
+		path+=query;
 	}
+	let method=request.method().clone();
 	let headers=request.headers();
-	println!("[{}] \"GET {}\" \"{:?}\"",chrono::Utc::now().format("%+"),url,headers.get(USER_AGENT).map(|s|s.to_str().map(|s|s.replace("\"","'"))));
-	let builder=ctx.client.get(url);
-	let builder=builder.headers(headers.clone());
-	let res=builder.send().await;
-	match res{
-		Ok(v)=>{
-			let mut header=axum::http::header::HeaderMap::new();
-			for (k,v) in v.headers(){
-				match k.as_str(){
-					"transfer-encoding"|"connection"=>{},
-					_=>{
-						header.append(k,v.clone());
-						//println!("header {} {:?}",k,v);
-					}
-				}
-			}
-			let status=v.status().as_u16();
-			let body=v.bytes().await.map(|b|b.to_vec()).unwrap_or_default();
-			(StatusCode::from_u16(status).unwrap_or(StatusCode::BAD_GATEWAY),header,body).into_response()
-		},
-		Err(e)=>{
-			eprintln!("{:?}",e);
-			StatusCode::BAD_GATEWAY.into_response()
+	//HTTP/2ではContent-Lengthが無くても本文が続くことがあるので、本文側の終端も確認する
+	let has_body=headers.contains_key(CONTENT_LENGTH)||headers.contains_key(TRANSFER_ENCODING)||!request.body().is_end_stream();
+	let cache_key=ctx.response_cache.cache_key(&method,request.uri(),headers);
+	if let Some(key)=cache_key.as_ref(){
+		if let Some(cached)=ctx.response_cache.get(key).await{
+			return cached.into_response("HIT");
 		}
 	}
-}
-
-async fn ws_backend(client:Client,backend_url:&str,token:Option<&str>)->Result<reqwest_websocket::WebSocket,String>{
-	use reqwest_websocket::RequestBuilderExt;
-	let url=reqwest::Url::parse(backend_url);
-	let mut url=match url {
-		Ok(url)=>url,
-		Err(e)=>{
-			eprintln!("{:?}",e);
-			panic!("URL parse error");
-		}
+	let mut headers=filter_hop_by_hop(headers);
+	crate::client_ip::set_forwarded_headers(&ctx.config.load(),&addr,&mut headers);
+	if cache_key.is_some(){
+		//Accept-Encoding毎に保存しなくて済むように無圧縮で受け取る
+		headers.remove(ACCEPT_ENCODING);
+	}
+	let mut body=if has_body{
+		Some(request.into_body())
+	}else{
+		None
 	};
-	if url.scheme()=="http"{
-		url.set_scheme("ws").unwrap();
+	//本文は再送できないので、本文のないリクエストだけ別のバックエンドで再試行する
+	let upstream_service=ctx.upstream_service.load();
+	let attempts=if has_body||backend.is_some(){
+		1
 	}else{
-		url.set_scheme("wss").unwrap();
-	}
-	url.set_path("streaming");
-	if let Some(token)=token{
-		let query=format!("i={}",token);
-		url.set_query(Some(&query));
+		upstream_service.len()
+	};
+	for _ in 0..attempts{
+		let upstream=match backend{
+			Some(name)=>upstream_service.pick_named(name),
+			None=>upstream_service.pick(),
+		};
+		let upstream=match upstream{
+			Some(upstream)=>upstream,
+			None=>break,
+		};
+		let url=format!("{}{}",upstream.url(),path);
+		let builder=ctx.client.request(method.clone(),url).headers(headers.clone());
+		let builder=match body.take(){
+			Some(body)=>builder.body(reqwest::Body::wrap_stream(body.into_data_stream())),
+			None=>builder,
+		};
+		match builder.send().await{
+			Ok(res)=>{
+				ctx.metrics.proxy_responses.with_label_values(&[upstream.name(),res.status().as_str()]).inc();
+				return match cache_key{
+					Some(key)=>cache_response(&ctx,key,res,upstream).await,
+					None=>backend_response(res,upstream),
+				}
+			},
+			Err(e)=>{
+				ctx.metrics.proxy_responses.with_label_values(&[upstream.name(),"error"]).inc();
+				tracing::warn!("backend {} {:?}",upstream.name(),e);
+				//接続できなかった場合のみ、リクエストは届いていないので再試行できる
+				if !e.is_connect(){
+					break;
+				}
+				upstream.mark_failed();
+			}
+		}
 	}
-	let response = client
-		.get(url)
-		.upgrade()
-		.send()
-		.await.map_err(|e|e.to_string())?;
-	let websocket = response.into_websocket().await.map_err(|e|e.to_string())?;
-	Ok(websocket)
+	StatusCode::BAD_GATEWAY.into_response()
 }
@@ -2,8 +2,10 @@ use axum::Router;
 
 use crate::Context;
 
-mod files;
+pub(super) mod files;
 
 pub fn route(ctx: &Context,app: Router)->Router{
-	files::route(ctx,app)
+	let app=files::route(ctx,app);
+	let ctx0=ctx.clone();
+	app.route_layer(axum::middleware::from_fn(move|req,next|crate::api::metrics::track_upload(ctx0.clone(),req,next)))
 }
@@ -1,13 +1,38 @@
-use axum::Router;
+use axum::{middleware::Next, Router};
 
 use crate::Context;
 
-mod create;
+pub(in crate::api) mod create;
+mod create_batch;
 mod multipart;
+mod upload_ticket;
 
 pub fn route(ctx: &Context,app: Router)->Router{
 	let ctx0=ctx.clone();
-	let app=app.route("/api/drive/files/create",axum::routing::post(move|multipart|create::post(ctx0.clone(),multipart)))
-		.layer(axum::extract::DefaultBodyLimit::max(ctx.config.full_upload_limit as usize));
+	let ctx1=ctx.clone();
+	let ctx2=ctx.clone();
+	let ctx3=ctx.clone();
+	let ctx4=ctx.clone();
+	let ctx5=ctx.clone();
+	let app=app.route("/api/drive/files/create",axum::routing::post(move|multipart|create::post(ctx0.clone(),multipart)).fallback(move|addr,req|crate::api::default_route::proxy(ctx1.clone(),addr,req)))
+		.route("/api/drive/files/create-batch",axum::routing::post(move|multipart|create_batch::post(ctx5.clone(),multipart)))
+		.route("/api/drive/files/upload-tickets",axum::routing::post(move|req|upload_ticket::post(ctx3.clone(),req)))
+		.route("/api/drive/files/upload-tickets/:ticket",axum::routing::post(move|ticket,multipart|upload_ticket::upload(ctx4.clone(),ticket,multipart)))
+		.layer(axum::middleware::from_fn(move|req,next|body_limit(ctx2.clone(),req,next)));
 	multipart::route(ctx,app)
 }
+/**
+ * full_upload_limitは設定の再読み込みで変わるため、リクエスト毎にその時点の値で制限する
+ */
+pub(in crate::api) async fn body_limit(
+	ctx:Context,
+	request: axum::extract::Request,
+	next:Next,
+)->axum::response::Response{
+	use tower::{Layer, ServiceExt};
+	let limit=ctx.config.load().full_upload_limit as usize;
+	match axum::extract::DefaultBodyLimit::max(limit).layer(next).oneshot(request).await{
+		Ok(res)=>res,
+		Err(e)=>match e{},
+	}
+}
@@ -1,35 +1,58 @@
 use core::str;
 use std::io::Write;
 
-use axum::{extract::Multipart, http::StatusCode, response::IntoResponse};
+use axum::{extract::Multipart, response::IntoResponse};
 
-use crate::{models::{access_token::MiAccessToken, user::MiUser}, Context};
+use crate::{error::ApiError, models::user::MiUser, service::drive::RegisterPreflightResult, Context};
 
 #[derive(Default,Debug)]
-struct RequestParms{
-	name:Option<String>,
-	ext:Option<String>,
-	i:Option<String>,
-	comment:Option<String>,
-	is_sensitive:bool,
-	size:u64,
+pub(in crate::api) struct RequestParms{
+	pub(in crate::api) name:Option<String>,
+	pub(in crate::api) ext:Option<String>,
+	pub(in crate::api) i:Option<String>,
+	pub(in crate::api) comment:Option<String>,
+	pub(in crate::api) is_sensitive:bool,
+	pub(in crate::api) size:u64,
+	pub(in crate::api) folder_id:Option<String>,
+	pub(in crate::api) force:bool,
+	//forceがfalseで同じハッシュのファイルが既にあれば、アップロードせずにそれを返す
+	//i,force,isSensitiveと共にfileより前に送られた場合はファイル本体を受信する前に確認する
+	//fileを受信した後は申告された値ではなく内容から計算した値で確認する
+	pub(in crate::api) md5:Option<String>,
+	pub(in crate::api) sha256:Option<String>,
 }
 
 pub async fn post(
 	ctx:Context,
 	mut multipart: Multipart,
 )->axum::response::Response{
-	println!("full upload");
+	tracing::debug!("full upload");
 	let mut req=RequestParms::default();
 	let mut file_data=None;
-	let mut force=false;
-	let mut folder_id=None;
+	let mut dedup_checked=false;
+	//fileより後に送られる値で結果が変わるので、早期の確認はこれらが揃っている場合に限る
+	let mut seen_i=false;
+	let mut seen_force=false;
+	let mut seen_sensitive=false;
 	while let Some(field) = multipart.next_field().await.unwrap_or(None) {
 		let name = field.name();
 		if name.is_none(){
 			continue;
 		}
 		let name = name.unwrap().to_string();
+		match name.as_str(){
+			"i"=>seen_i=true,
+			"force"=>seen_force=true,
+			"isSensitive"=>seen_sensitive=true,
+			_=>{},
+		}
+		let early=seen_i&&seen_force&&seen_sensitive;
+		if &name=="file"&&early&&!req.force&&(req.md5.is_some()||req.sha256.is_some()){
+			dedup_checked=true;
+			if let Some(res)=find_duplicate(&ctx,&req).await{
+				return res;
+			}
+		}
 		let data=field.bytes().await;
 		if data.is_err(){
 			continue;
@@ -43,7 +66,7 @@ pub async fn post(
 			req.ext=String::from_utf8(data.to_vec()).ok();
 		}
 		if &name=="folder_id"{
-			folder_id=String::from_utf8(data.to_vec()).ok();
+			req.folder_id=String::from_utf8(data.to_vec()).ok();
 		}
 		if &name=="comment"{
 			req.comment=String::from_utf8(data.to_vec()).ok();
@@ -62,12 +85,18 @@ pub async fn post(
 			}
 		}
 		if &name=="force"{
-			force=match str::from_utf8(&data){
+			req.force=match str::from_utf8(&data){
 				Ok("true")=>true,
 				Ok("false")=>false,
 				_=>false,
 			}
 		}
+		if &name=="md5"{
+			req.md5=String::from_utf8(data.to_vec()).ok();
+		}
+		if &name=="sha256"{
+			req.sha256=String::from_utf8(data.to_vec()).ok();
+		}
 		if &name=="size"{
 			req.size=match str::from_utf8(&data){
 				Ok(s)=>u64::from_str_radix(s,10).unwrap_or_default(),
@@ -79,13 +108,72 @@ pub async fn post(
 		}
 	}
 	if file_data.is_none(){
-		let mut header=axum::http::header::HeaderMap::new();
-		header.insert(axum::http::header::ACCESS_CONTROL_ALLOW_ORIGIN,"*".parse().unwrap());
-		header.insert("X-ErrorStatus","NO DATA".parse().unwrap());
-		return (axum::http::StatusCode::BAD_REQUEST,header).into_response();
+		return ApiError::FileRequired.into_response();
 	}
 	let file_data=file_data.unwrap();
-
+	if !dedup_checked&&!req.force&&(req.md5.is_some()||req.sha256.is_some()){
+		//fileより後にハッシュが送られた場合でもS3への保存は省ける
+		let (md5sum,sha256sum)=content_hashes(&file_data);
+		req.md5=Some(md5sum);
+		req.sha256=Some(sha256sum);
+		if let Some(res)=find_duplicate(&ctx,&req).await{
+			return res;
+		}
+	}
+	//let offset_time=chrono::Utc::now();
+	let me=match authenticate(&ctx,req.i.as_deref()).await{
+		Ok(user)=>user,
+		Err(e)=>return e.into_response(),
+	};
+	crate::api::access_log::record_user(&me.id);
+	store(&ctx,&me,req,file_data,None).await
+}
+/**
+ * 受信したファイルを保存して登録する
+ * allowed_typesがある場合は判定したContent-Typeがそのどれかに一致する必要がある
+ */
+pub(super) async fn store(
+	ctx:&Context,
+	me:&MiUser,
+	mut req:RequestParms,
+	file_data:axum::body::Bytes,
+	allowed_types:Option<&[String]>,
+)->axum::response::Response{
+	let content_type=detect_content_type(&file_data,&mut req);
+	if let Some(allowed_types)=allowed_types{
+		if !allowed_types.iter().any(|allowed|mime_matches(allowed,content_type)){
+			return ApiError::InvalidParam(format!("{} is not allowed",content_type)).into_response();
+		}
+	}
+	tracing::debug!("call register_preflight");
+	let register_preflight_result=ctx.drive_service.register_preflight(
+		Some(me),
+		req.size as i64,
+		req.name.as_deref().unwrap_or_default(),
+		req.ext.as_deref(),
+		false,
+		req.folder_id.as_deref(),
+	).await;
+	//println!("preflight{}ms",(chrono::Utc::now()-offset_time).num_milliseconds());
+	if let Err(e)=register_preflight_result{
+		return ApiError::from(e).into_response();
+	}
+	let res=register_preflight_result.unwrap();
+	//println!("PREFLIGHT {:?}",res);
+	match save(ctx,me,&req,file_data,content_type,res,None).await{
+		Ok(res)=>{
+			let mut header=axum::http::header::HeaderMap::new();
+			header.insert(axum::http::header::CONTENT_TYPE,"application/json".parse().unwrap());
+			let status=axum::http::StatusCode::from_u16(200).unwrap_or(axum::http::StatusCode::BAD_GATEWAY);
+			(status,header,serde_json::to_string(&res.unwrap_or(serde_json::Value::Null)).unwrap_or_default()).into_response()
+		},
+		Err(e)=>e.into_response(),
+	}
+}
+/**
+ * 内容からContent-Typeを判定してreq.extを合わせる
+ */
+pub(in crate::api) fn detect_content_type(file_data:&[u8],req:&mut RequestParms)->&'static str{
 	let mut content_type="";
 	if let Some(kind)=infer::get(&file_data){
 		content_type=kind.mime_type();
@@ -110,47 +198,36 @@ pub async fn post(
 		content_type = "application/octet-stream";
 		req.ext = None;
 	}
-	//let offset_time=chrono::Utc::now();
-	let mut user=None;
-	let mut register_preflight_result=Err(crate::service::drive::RegisterPreflightError::InternalServerError);
-	if let Some(token)=req.i.as_ref(){
-		if let Some(mut con)=ctx.raw_db.get().await{
-			let db_token=MiAccessToken::load_by_id(&mut con, &token).await;
-			user=match db_token{
-				Some(token)=>MiUser::load_by_id(&mut con,&token.user_id).await,
-				None=>MiUser::load_by_token(&mut con,&token).await
-			};
-			if let Some(me)=user.as_ref(){
-				println!("call register_preflight");
-				register_preflight_result=ctx.drive_service.register_preflight(
-					Some(&me),
-					req.size as i64,
-					req.name.as_deref().unwrap_or_default(),
-					req.ext.as_deref(),
-					false,
-					folder_id.as_deref(),
-				).await;
-			}else{
-				println!("not found MiUser");
-			}
-		}else{
-			let mut header=axum::http::header::HeaderMap::new();
-			header.insert("X-ErrorStatus","DB Pool".parse().unwrap());
-			return (axum::http::StatusCode::INTERNAL_SERVER_ERROR,header).into_response();
-		}
-	}else{
-		println!("No Token")
-	}
-	//println!("preflight{}ms",(chrono::Utc::now()-offset_time).num_milliseconds());
-	if let Err(e)=register_preflight_result{
-		let mut header=axum::http::header::HeaderMap::new();
-		header.insert("X-ErrorStatus",format!("{:?}",e).parse().unwrap());
-		return (axum::http::StatusCode::BAD_REQUEST,header).into_response();
-	}
-	let res=register_preflight_result.unwrap();
-	//println!("PREFLIGHT {:?}",res);
-
-	let s3_key=format!("{}/{}{}",ctx.config.prefix,uuid::Uuid::new_v4().to_string(),req.ext.as_ref().map(|s|s.as_str()).unwrap_or(""));
+	content_type
+}
+/**
+ * 内容の(md5,sha256)を16進数の文字列で返す
+ */
+fn content_hashes(data:&[u8])->(String,String){
+	use sha2::{Sha256, Digest};
+	let md5sum=format!("{:x}",md5::compute(data));
+	let sha256sum=Sha256::digest(data).iter().map(|n| format!("{:02x}", n)).collect::<String>();
+	(md5sum,sha256sum)
+}
+/**
+ * register_preflightを通ったファイルをS3に保存して登録し、packしたものを返す
+ */
+pub(in crate::api) async fn save(
+	ctx:&Context,
+	me:&MiUser,
+	req:&RequestParms,
+	file_data:axum::body::Bytes,
+	content_type:&str,
+	res:RegisterPreflightResult,
+	file_id:Option<String>,
+)->Result<Option<serde_json::Value>,ApiError>{
+	let bucket=ctx.bucket.load();
+	let s3_key=format!("{}/{}{}",ctx.config.load().prefix,uuid::Uuid::new_v4().to_string(),req.ext.as_ref().map(|s|s.as_str()).unwrap_or(""));
+	let sha256sum={
+		use sha2::{Sha256, Digest};
+		let hash=Sha256::digest(&file_data);
+		hash.iter().map(|n| format!("{:02x}", n)).collect::<String>()
+	};
 	let mut md5sum=md5::Context::new();
 	let (md5sum,content_md5) =match md5sum.write_all(&file_data){
 		Ok(_)=>{
@@ -163,13 +240,13 @@ pub async fn post(
 	};
 
 	//let offset_time=chrono::Utc::now();
-	let thumbnail_size=2048;
+	let thumbnail_size=crate::service::file_meta::THUMBNAIL_SIZE;
 	let cache_control="max-age=31536000, immutable";
 	let detected_name=percent_encoding::percent_encode(res.detected_name.as_bytes(), percent_encoding::NON_ALPHANUMERIC);
 	let content_disposition=format!("inline; filename=\"{}\"",detected_name);
 	
 	let (raw_upload,(mut thumbnail_upload,mut info))=futures_util::join!(
-		ctx.bucket.put_object_with_metadata(&s3_key,&file_data,&content_type,content_md5.clone(),cache_control,&content_disposition),
+		bucket.put_object_with_metadata(&s3_key,&file_data,&content_type,content_md5.clone(),cache_control,&content_disposition),
 		async{
 			let info=match image::load_from_memory(&file_data){
 				Ok(img)=>ctx.file_service.metadata(
@@ -177,16 +254,16 @@ pub async fn post(
 					res.sensitive_threshold,
 					res.skip_sensitive_detection,
 					thumbnail_size,
-					ctx.config.thumbnail_quality,
-					ctx.config.thumbnail_filter.into(),
+					ctx.config.load().thumbnail_quality,
+					ctx.config.load().thumbnail_filter.into(),
 				).await,
 				_=>Default::default(),
 			};
 			let thumbnail_bin=info.thumbnail.as_ref();
 			(match thumbnail_bin{
 				Some(thumbnail_bin)=>{
-					let thumbnail_key=format!("{}/thumbnail-{}{}",ctx.config.prefix,uuid::Uuid::new_v4().to_string(),".webp");
-					match ctx.bucket.put_object_with_metadata(&thumbnail_key,&thumbnail_bin,"image/webp",s3::command::ContentMd5::Auto,cache_control,&content_disposition).await{
+					let thumbnail_key=format!("{}/thumbnail-{}{}",ctx.config.load().prefix,uuid::Uuid::new_v4().to_string(),".webp");
+					match bucket.put_object_with_metadata(&thumbnail_key,&thumbnail_bin,"image/webp",s3::command::ContentMd5::Auto,cache_control,&content_disposition).await{
 						Ok(_)=>Ok(Some(thumbnail_key)),
 						Err(e)=>Err(e),
 					}
@@ -196,12 +273,12 @@ pub async fn post(
 		}
 	);
 	if content_type.starts_with("video/"){
-		info=ctx.file_service.ffmpeg_metadata(&ctx.config,&s3_key,thumbnail_size,res.sensitive_threshold,res.skip_sensitive_detection).await.unwrap_or_default();
+		info=ctx.file_service.ffmpeg_metadata(&ctx.config.load(),&s3_key,thumbnail_size,res.sensitive_threshold,res.skip_sensitive_detection).await.unwrap_or_default();
 		let thumbnail_bin=info.thumbnail.as_ref();
 		thumbnail_upload=match thumbnail_bin{
 			Some(thumbnail_bin)=>{
-				let thumbnail_key=format!("{}/thumbnail-{}{}",ctx.config.prefix,uuid::Uuid::new_v4().to_string(),".webp");
-				match ctx.bucket.put_object_with_metadata(&thumbnail_key,&thumbnail_bin,"image/webp",s3::command::ContentMd5::Auto,cache_control,&content_disposition).await{
+				let thumbnail_key=format!("{}/thumbnail-{}{}",ctx.config.load().prefix,uuid::Uuid::new_v4().to_string(),".webp");
+				match bucket.put_object_with_metadata(&thumbnail_key,&thumbnail_bin,"image/webp",s3::command::ContentMd5::Auto,cache_control,&content_disposition).await{
 					Ok(_)=>Ok(Some(thumbnail_key)),
 					Err(e)=>Err(e),
 				}
@@ -218,8 +295,8 @@ pub async fn post(
 	match raw_upload{
 		Ok(_resp) => {},
 		Err(e) =>{
-			eprintln!("{}:{} {:?}",file!(),line!(),e);
-			return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+			ctx.metrics.s3_error("put_object");
+			return Err(e.into());
 		},
 	}
 	let thumbnail_key=match thumbnail_upload{
@@ -227,14 +304,14 @@ pub async fn post(
 			key
 		},
 		Err(e) =>{
-			eprintln!("{}:{} {:?}",file!(),line!(),e);
-			return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+			ctx.metrics.s3_error("put_thumbnail");
+			return Err(e.into());
 		},
 	};
 	let res=ctx.drive_service.register_file(
-		user.as_ref(),
+		Some(me),
 		s3_key.as_str(),
-		folder_id.as_deref(),
+		req.folder_id.as_deref(),
 		req.comment.as_deref(),
 		info.blurhash.as_deref(),
 		false,
@@ -249,17 +326,49 @@ pub async fn post(
 		md5sum,
 		content_type.to_owned(),
 		file_data.len() as i64,
-		force,
+		req.force,
 		thumbnail_key.as_deref(),
-		ctx.config.public_base_url.clone(),
+		ctx.config.load().public_base_url.clone(),
+		file_id,
 	).await;
 	if res.is_none(){
-		return (axum::http::StatusCode::BAD_REQUEST).into_response();
+		return Err(ApiError::internal("register_file"));
 	}
 	let res=res.unwrap();
-	//let (status,res)=res.unwrap();
+	ctx.drive_service.record_sha256(&me.id,&sha256sum,&res.0.id).await;
+	Ok(res.1)
+}
+pub(in crate::api) async fn authenticate(ctx:&Context,token:Option<&str>)->Result<MiUser,ApiError>{
+	let token=match token{
+		Some(token)=>token,
+		None=>{
+			tracing::debug!("No Token");
+			return Err(ApiError::CredentialRequired);
+		}
+	};
+	ctx.user_service.authenticate(token).await.ok_or_else(||{
+		tracing::debug!("not found MiUser");
+		ApiError::AuthenticationFailed
+	})
+}
+/**
+ * 申告されたハッシュと同じファイルがあればそれを返すレスポンスを作る
+ */
+async fn find_duplicate(ctx:&Context,req:&RequestParms)->Option<axum::response::Response>{
+	let me=match authenticate(ctx,req.i.as_deref()).await{
+		Ok(me)=>me,
+		Err(e)=>return Some(e.into_response()),
+	};
+	let (_file,packed)=ctx.drive_service.find_duplicate(&me,req.md5.as_deref(),req.sha256.as_deref(),req.is_sensitive).await?;
+	crate::api::access_log::record_user(&me.id);
 	let mut header=axum::http::header::HeaderMap::new();
 	header.insert(axum::http::header::CONTENT_TYPE,"application/json".parse().unwrap());
-	let status=axum::http::StatusCode::from_u16(200).unwrap_or(axum::http::StatusCode::BAD_GATEWAY);
-	(status,header,serde_json::to_string(&res.1.unwrap_or(serde_json::Value::Null)).unwrap_or_default()).into_response()
+	Some((axum::http::StatusCode::OK,header,serde_json::to_string(&packed.unwrap_or(serde_json::Value::Null)).unwrap_or_default()).into_response())
+}
+//サブタイプを*にしたもの(image/*など)は同じ主タイプ全てに一致する
+fn mime_matches(allowed:&str,content_type:&str)->bool{
+	match allowed.strip_suffix("/*"){
+		Some(main)=>content_type.split('/').next()==Some(main),
+		None=>allowed.eq_ignore_ascii_case(content_type),
+	}
 }
@@ -0,0 +1,112 @@
+use core::str;
+
+use axum::{extract::Multipart, response::IntoResponse};
+use futures::StreamExt;
+
+use crate::{error::ApiError, Context};
+
+use super::create::RequestParms;
+
+//1回のリクエストで受け付けるファイル数の上限
+const MAX_BATCH_FILES:usize=32;
+//同時に保存するファイル数
+const BATCH_CONCURRENCY:usize=4;
+
+/**
+ * 複数のファイルを1回でアップロードする
+ * i,folderId,forceは全体で共通、name,comment,isSensitiveはその後のfileにだけ適用する
+ * 結果はファイルの順に{"file":...}か{"error":...}の配列で返す
+ */
+pub async fn post(
+	ctx:Context,
+	mut multipart: Multipart,
+)->axum::response::Response{
+	let mut token=None;
+	let mut folder_id=None;
+	let mut force=false;
+	let mut next=RequestParms::default();
+	let mut files=vec![];
+	while let Some(field) = multipart.next_field().await.unwrap_or(None) {
+		let name = match field.name(){
+			Some(name)=>name.to_owned(),
+			None=>continue,
+		};
+		let file_name=field.file_name().map(|s|s.to_owned());
+		let data=match field.bytes().await{
+			Ok(data)=>data,
+			Err(_)=>continue,
+		};
+		match name.as_str(){
+			"i"=>token=String::from_utf8(data.to_vec()).ok(),
+			"folderId"|"folder_id"=>folder_id=String::from_utf8(data.to_vec()).ok(),
+			"force"=>force=str::from_utf8(&data)==Ok("true"),
+			"name"=>next.name=String::from_utf8(data.to_vec()).ok(),
+			"comment"=>next.comment=String::from_utf8(data.to_vec()).ok(),
+			"isSensitive"=>next.is_sensitive=str::from_utf8(&data)==Ok("true"),
+			"file"=>{
+				if files.len()>=MAX_BATCH_FILES{
+					return ApiError::InvalidParam(format!("up to {} files",MAX_BATCH_FILES)).into_response();
+				}
+				let mut req=std::mem::take(&mut next);
+				if req.name.is_none(){
+					req.name=file_name;
+				}
+				req.size=data.len() as u64;
+				files.push((req,data));
+			},
+			_=>{},
+		}
+	}
+	if files.is_empty(){
+		return ApiError::FileRequired.into_response();
+	}
+	let me=match super::create::authenticate(&ctx,token.as_deref()).await{
+		Ok(me)=>me,
+		Err(e)=>return e.into_response(),
+	};
+	crate::api::access_log::record_user(&me.id);
+	//ファイル単位の制限は一番大きいもので、ドライブの容量は合計で1回だけ確認する
+	let max_size=files.iter().map(|(req,_)|req.size).max().unwrap_or_default();
+	let total_size:u64=files.iter().map(|(req,_)|req.size).sum();
+	let preflight=match ctx.drive_service.register_preflight(
+		Some(&me),
+		max_size as i64,
+		"",
+		None,
+		false,
+		folder_id.as_deref(),
+	).await{
+		Ok(res)=>res,
+		Err(e)=>return ApiError::from(e).into_response(),
+	};
+	//保存中の分は進行中の分割アップロードと同じく予約しておく
+	let batch_id=uuid::Uuid::new_v4().to_string();
+	match ctx.quota_service.reserve(&me.id,&batch_id,total_size,preflight.free_space,ctx.config.load().session_ttl).await{
+		Ok(true)=>{},
+		Ok(false)=>return ApiError::NoFreeSpace.into_response(),
+		Err(e)=>return ApiError::from(e).into_response(),
+	}
+	let _reservation=ctx.quota_service.guard(&me.id,&batch_id);
+	let results=futures::stream::iter(files.into_iter().map(|(mut req,data)|{
+		let ctx=&ctx;
+		let me=&me;
+		let mut preflight=preflight.clone();
+		req.folder_id=folder_id.clone();
+		req.force=force;
+		async move{
+			let content_type=super::create::detect_content_type(&data,&mut req);
+			preflight.detected_name=crate::service::drive::detect_name(req.name.as_deref().unwrap_or_default(),req.ext.as_deref());
+			match super::create::save(ctx,me,&req,data,content_type,preflight,None).await{
+				Ok(file)=>serde_json::json!({
+					"file":file,
+				}),
+				Err(e)=>serde_json::json!({
+					"error":e.to_json(),
+				}),
+			}
+		}
+	})).buffered(BATCH_CONCURRENCY).collect::<Vec<_>>().await;
+	let mut header=axum::http::header::HeaderMap::new();
+	header.insert(axum::http::header::CONTENT_TYPE,"application/json".parse().unwrap());
+	(axum::http::StatusCode::OK,header,serde_json::Value::Array(results).to_string()).into_response()
+}
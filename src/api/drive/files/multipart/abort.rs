@@ -7,12 +7,16 @@ pub async fn post(
 		request: axum::extract::Request,
 	)->axum::response::Response{
 	let authorization=request.headers().get("Authorization");
-	let (session,_hashed_sid)=match ctx.upload_session(authorization,true).await{
+	let (session,hashed_sid)=match ctx.upload_session(authorization,true).await{
 		Ok(v)=>v,
-		Err(e)=>return e,
+		Err(e)=>return e.into_response(),
 	};
 	if let Some(upload_id)=session.upload_id.as_ref(){
-		let _=ctx.bucket.abort_upload(&session.s3_key,upload_id).await;
+		if ctx.bucket.load().abort_upload(&session.s3_key,upload_id).await.is_err(){
+			ctx.metrics.s3_error("abort_upload");
+		}
 	}
+	ctx.quota_service.release(&session.user_id,&hashed_sid).await;
+	ctx.metrics.multipart_sessions.with_label_values(&["aborted"]).inc();
 	(StatusCode::NO_CONTENT).into_response()
 }
@@ -1,110 +1,165 @@
-use axum::{http::StatusCode, response::IntoResponse};
+use std::{sync::Arc, time::Duration};
+
+use axum::response::IntoResponse;
 use futures::TryStreamExt;
-use redis::AsyncCommands;
 use serde::{Deserialize, Serialize};
-use tokio::io::AsyncReadExt;
+use tokio::{io::AsyncReadExt, sync::broadcast};
 use tokio_util::io::StreamReader;
 
-use crate::{models::user::MiUser, Context, UploadSession};
+use crate::{error::ApiError, models::user::MiUser, service::event::{DriveEventType, StreamChannels, StreamEvent}, Context, UploadSession};
+
+//pubsubの取りこぼしに備えて、進捗のイベントが届かなくてもこの間隔で確認し直す
+const PART_RECHECK_INTERVAL:Duration=Duration::from_secs(5);
 
 #[derive(Debug, Serialize,Deserialize)]
 pub struct RequestBody{
 	i: String,//トークン必須
+	//direct uploadの場合に必須、S3が返したパートのETagを順番に並べたもの
+	#[serde(default)]
+	etags:Option<Vec<String>>,
+	//direct uploadの場合に指定するとアップロードされた内容と照合する
+	#[serde(default)]
+	md5:Option<String>,
 }
 pub async fn post(
 	mut ctx:Context,
 	request: axum::extract::Request,
 )->axum::response::Response{
 	let authorization=request.headers().get("Authorization");
-	let (session,_hashed_sid)=match ctx.upload_session(authorization,true).await{
+	let (session,hashed_sid)=match ctx.upload_session(authorization,true).await{
 		Ok(v)=>v,
-		Err(e)=>return e,
+		Err(e)=>return e.into_response(),
 	};
+	let user_id=session.user_id.clone();
+	let res=finish(&mut ctx,session,&hashed_sid,request).await;
+	//成功した場合はregister_fileで使用量に含まれているので、結果に関わらず予約を解放する
+	ctx.quota_service.release(&user_id,&hashed_sid).await;
+	if !res.status().is_success(){
+		publish_phase(&ctx,&user_id,&hashed_sid,"failed",None).await;
+	}
+	res
+}
+/**
+ * ドライブストリームにfinish-upload以降の処理段階を通知する
+ * waitingParts,completing,verifying,processing,registering,doneの順に進み、途中で失敗したらfailed
+ */
+async fn publish_phase(ctx:&Context,user_id:&String,hashed_sid:&str,phase:&str,file_id:Option<&str>){
+	let body=serde_json::json!({
+		"sessionId":hashed_sid,
+		"phase":phase,
+		"fileId":file_id,
+	});
+	if let Err(e)=ctx.event_service.publish_drive_stream(user_id,Some(DriveEventType::FileProcessing),Some(body)).await{
+		tracing::debug!("{:?}",e);
+	}
+}
+/**
+ * セッションは取り出し済みなので、完了前に失敗した場合はS3の分割アップロードも破棄する
+ */
+async fn fail(ctx:&Context,session:&UploadSession,e:ApiError)->axum::response::Response{
+	if let Some(upload_id)=session.upload_id.as_ref(){
+		if ctx.bucket.load().abort_upload(&session.s3_key,upload_id).await.is_err(){
+			ctx.metrics.s3_error("abort_upload");
+		}
+	}
+	ctx.metrics.multipart_sessions.with_label_values(&["failed"]).inc();
+	e.into_response()
+}
+async fn finish(
+	ctx:&mut Context,
+	mut session:UploadSession,
+	hashed_sid:&str,
+	request: axum::extract::Request,
+)->axum::response::Response{
 	let stream=request.into_body().into_data_stream();
 	let body_with_io_error = stream.map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err));
 	let mut body_reader = StreamReader::new(body_with_io_error);
 	let mut buf=vec![];
-	if let Err(_e)=body_reader.read_to_end(&mut buf).await{
-		return (StatusCode::INTERNAL_SERVER_ERROR).into_response();
+	if let Err(e)=body_reader.read_to_end(&mut buf).await{
+		return fail(ctx,&session,ApiError::internal(e)).await;
 	}
 	let q=match serde_json::from_slice::<RequestBody>(&buf){
 		Ok(v)=>v,
 		Err(e)=>{
-			eprintln!("{}:{} {:?}",file!(),line!(),e);
-			return (StatusCode::BAD_REQUEST).into_response()
+			tracing::debug!("{:?}",e);
+			return fail(ctx,&session,ApiError::InvalidParam(e.to_string())).await;
 		}
 	};
-	let mut parts=vec![];
-	let mut part_number=1;
-	for etag in session.part_etag.iter(){
-		async fn err_handle(ctx: &Context,session: &UploadSession)->axum::response::Response{
-			if let Some(upload_id)=session.upload_id.as_ref(){
-				let _=ctx.bucket.abort_upload(&session.s3_key,upload_id).await;
-			}
-			(StatusCode::INTERNAL_SERVER_ERROR).into_response()
-		}
-		let mut error_count=0;
-		let tag;
-		loop{
-			match ctx.redis.get_del::<&String,String>(&etag).await{
-				Ok(etag)=>{
-					if etag.is_empty(){
-						return err_handle(&ctx,&session).await;
-					}
-					tag=etag;
-					break;
-				},
-				Err(e)=>{
-					error_count+=1;
-					if error_count>10*60{//10分間毎秒確認
-						eprintln!("{}:{} {:?}",file!(),line!(),e);
-						return err_handle(&ctx,&session).await;
-					}else{
-						tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-					}
-				}
-			}
+	let etags=if session.direct{
+		match q.etags{
+			Some(etags) if Some(etags.len() as u32)==session.part_number.map(|n|n+1)=>Ok(etags),
+			Some(_)=>Err(ApiError::InvalidParam("etags count mismatch".to_owned())),
+			None=>Err(ApiError::InvalidParam("etags is required for direct upload".to_owned())),
 		}
-		parts.push(s3::serde_types::Part{
-			part_number,
-			etag:tag,
-		});
-		part_number+=1;
-	}
+	}else{
+		publish_phase(ctx,&session.user_id,hashed_sid,"waitingParts",None).await;
+		wait_parts(ctx,&session,hashed_sid).await
+	};
+	let etags=match etags{
+		Ok(etags)=>etags,
+		Err(e)=>return fail(ctx,&session,e).await,
+	};
+	let parts:Vec<_>=etags.into_iter().enumerate().map(|(i,etag)|s3::serde_types::Part{
+		part_number:i as u32+1,
+		etag,
+	}).collect();
+	let part_number=parts.len() as u32+1;
 	if let Some(n)=session.part_number{
 		if part_number!=n+2{
-			eprintln!("{}:{} {}!={}",file!(),line!(),part_number,n+2);
-			return (StatusCode::BAD_REQUEST).into_response();
+			tracing::error!("part count mismatch {}!={}",part_number,n+2);
+			return fail(ctx,&session,ApiError::InvalidParam("part count mismatch".to_owned())).await;
 		}
 	}else{
-		eprintln!("{}:{}",file!(),line!());
-		return (StatusCode::BAD_REQUEST).into_response();
+		tracing::error!("no part uploaded");
+		return fail(ctx,&session,ApiError::InvalidParam("no part uploaded".to_owned())).await;
 	}
-	let md5sum=crate::md5_ontext_from_raw(&session.md5_ctx_64);
-	let md5sum=md5sum.compute().0;
-	let md5sum = md5sum.iter().map(|n| format!("{:02x}", n)).collect::<String>();
 	let cache_control="max-age=31536000, immutable";
 	let detected_name=percent_encoding::percent_encode(session.name.as_bytes(), percent_encoding::NON_ALPHANUMERIC);
 	let content_disposition=format!("inline; filename=\"{}\"",detected_name);
 	if session.upload_id.is_none(){
-		return (StatusCode::INTERNAL_SERVER_ERROR).into_response();
+		return ApiError::internal("no upload_id").into_response();
 	}
-	match ctx.bucket.complete_multipart_upload_with_metadata(&session.s3_key,&session.upload_id.unwrap(),parts,Some(&cache_control),Some(&content_disposition)).await{
+	publish_phase(ctx,&session.user_id,hashed_sid,"completing",None).await;
+	match ctx.bucket.load().complete_multipart_upload_with_metadata(&session.s3_key,session.upload_id.as_ref().unwrap(),parts,Some(&cache_control),Some(&content_disposition)).await{
 		Ok(_resp) => {},
 		Err(e) =>{
-			println!("{:?} \n{}",session.part_etag,session.content_length);
-			eprintln!("{}:{} {:?}",file!(),line!(),e);
-			return (axum::http::StatusCode::INTERNAL_SERVER_ERROR).into_response();
+			tracing::debug!("{:?} \n{}",session.part_etag,session.content_length);
+			ctx.metrics.s3_error("complete_multipart_upload");
+			//direct uploadではクライアントが送ったETagが誤っている場合もある
+			return fail(ctx,&session,e.into()).await;
 		},
 	}
+	let mut sha256sum=None;
+	let md5sum=if session.direct{
+		//クライアントが送った内容はこのサーバーを通っていないので、S3から読み直して確認する
+		publish_phase(ctx,&session.user_id,hashed_sid,"verifying",None).await;
+		match verify_direct(ctx,&mut session,q.md5.as_deref()).await{
+			Ok((md5sum,sha256))=>{
+				sha256sum=Some(sha256);
+				md5sum
+			},
+			Err(e)=>{
+				if ctx.bucket.load().delete_object(&session.s3_key).await.is_err(){
+					ctx.metrics.s3_error("delete_object");
+				}
+				ctx.metrics.multipart_sessions.with_label_values(&["failed"]).inc();
+				return e.into_response();
+			}
+		}
+	}else{
+		let md5sum=crate::md5_ontext_from_raw(&session.md5_ctx_64);
+		let md5sum=md5sum.compute().0;
+		md5sum.iter().map(|n| format!("{:02x}", n)).collect::<String>()
+	};
 	let mut thumbnail_key=None;
 	let mut width=0;
 	let mut height=0;
 	let mut blurhash=None;
 	let mut maybe_sensitive=false;
 	if session.content_type.starts_with("video/"){
+		publish_phase(ctx,&session.user_id,hashed_sid,"processing",None).await;
 		//let start_time=chrono::Utc::now();
-		if let Some(info)=ctx.file_service.ffmpeg_metadata(&ctx.config,&session.s3_key,2048,session.sensitive_threshold,session.skip_sensitive_detection).await{
+		if let Some(info)=ctx.file_service.ffmpeg_metadata(&ctx.config.load(),&session.s3_key,crate::service::file_meta::THUMBNAIL_SIZE,session.sensitive_threshold,session.skip_sensitive_detection).await{
 			width=info.width;
 			height=info.height;
 			blurhash=info.blurhash;
@@ -116,10 +171,13 @@ pub async fn post(
 					let cache_control="max-age=31536000, immutable";
 					let detected_name=percent_encoding::percent_encode(session.name.as_bytes(), percent_encoding::NON_ALPHANUMERIC);
 					let content_disposition=format!("inline; filename=\"{}\"",detected_name);
-					let thumbnail_key=format!("{}/thumbnail-{}{}",ctx.config.prefix,uuid::Uuid::new_v4().to_string(),".webp");
-					match ctx.bucket.put_object_with_metadata(&thumbnail_key,&thumbnail_bin,"image/webp",s3::command::ContentMd5::Auto,cache_control,&content_disposition).await{
+					let thumbnail_key=format!("{}/thumbnail-{}{}",ctx.config.load().prefix,uuid::Uuid::new_v4().to_string(),".webp");
+					match ctx.bucket.load().put_object_with_metadata(&thumbnail_key,&thumbnail_bin,"image/webp",s3::command::ContentMd5::Auto,cache_control,&content_disposition).await{
 						Ok(_)=>Some(thumbnail_key),
-						Err(_)=>None,
+						Err(_)=>{
+							ctx.metrics.s3_error("put_thumbnail");
+							None
+						},
 					}
 				},
 				None=>None
@@ -134,10 +192,10 @@ pub async fn post(
 	let user=match user{
 		Some(u)=>u,
 		None=>{
-			eprintln!("{}:{} NoUser",file!(),line!());
-			return (axum::http::StatusCode::INTERNAL_SERVER_ERROR).into_response();
+			return ApiError::internal("NoUser").into_response();
 		}
 	};
+	publish_phase(ctx,&session.user_id,hashed_sid,"registering",None).await;
 	let res=ctx.drive_service.register_file(
 		Some(&user),
 		session.s3_key.as_str(),
@@ -158,15 +216,154 @@ pub async fn post(
 		session.content_length as i64,
 		session.force,
 		thumbnail_key.as_deref(),
-		ctx.config.public_base_url.clone(),
+		ctx.config.load().public_base_url.clone(),
+		None,
 	).await;
 	if let None=res{
-		return (axum::http::StatusCode::BAD_REQUEST).into_response();
+		return ApiError::internal("register_file").into_response();
+	}
+	let (file,res)=res.unwrap();
+	if let Some(sha256sum)=sha256sum{
+		ctx.drive_service.record_sha256(&user.id,&sha256sum,&file.id).await;
 	}
-	let (_file,res)=res.unwrap();
+	publish_phase(ctx,&session.user_id,hashed_sid,"done",Some(&file.id)).await;
+	ctx.metrics.multipart_sessions.with_label_values(&["finished"]).inc();
 	let mut header=axum::http::header::HeaderMap::new();
 	header.insert(axum::http::header::CONTENT_TYPE,"application/json".parse().unwrap());
 	let status=axum::http::StatusCode::OK;
 	let res=serde_json::to_string(&res.unwrap_or(serde_json::Value::Null)).unwrap_or_default();
 	(status,header,res).into_response()
 }
+//保存済みのパート数とバイト数
+pub(super) fn progress_key(hashed_sid:&str)->String{
+	format!("multipartUploadProgress:{}",hashed_sid)
+}
+/**
+ * 全てのパートのアップロード完了を待ってETagを返す
+ * パートの完了毎にドライブのストリームへ進捗が配信されるので、それを待って未完了のものを確認し直す
+ */
+async fn wait_parts(ctx:&Context,session:&UploadSession,hashed_sid:&str)->Result<Vec<String>,ApiError>{
+	let timeout=std::time::Duration::from_secs(ctx.config.load().part_wait_timeout.unwrap_or(600));
+	let deadline=std::time::Instant::now()+timeout;
+	let mut etags:Vec<Option<String>>=vec![None;session.part_etag.len()];
+	//確認している間に配信されたイベントを逃さないように先に購読する
+	let mut events=ctx.event_service.subscribe();
+	let channel=StreamChannels::Drive(&session.user_id).channel_id();
+	let mut redis=ctx.redis.clone();
+	loop{
+		let pending:Vec<usize>=etags.iter().enumerate().filter(|(_,etag)|etag.is_none()).map(|(i,_)|i).collect();
+		if pending.is_empty(){
+			break;
+		}
+		let keys:Vec<&String>=pending.iter().map(|i|&session.part_etag[*i]).collect();
+		let values=redis::cmd("MGET").arg(&keys).query_async::<Vec<Option<String>>>(&mut redis).await?;
+		for (i,value) in pending.iter().zip(values.into_iter()){
+			match value{
+				Some(etag) if etag.is_empty()=>{
+					tracing::error!("part {} upload failed",i);
+					return Err(ApiError::PartUploadFailed{
+						part_number:*i as u32,
+					});
+				},
+				Some(etag)=>etags[*i]=Some(etag),
+				None=>{},
+			}
+		}
+		let first_pending=match etags.iter().position(|etag|etag.is_none()){
+			Some(i)=>i,
+			None=>break,
+		};
+		let remaining=deadline.saturating_duration_since(std::time::Instant::now());
+		if remaining.is_zero(){
+			tracing::error!("part {} upload timeout",first_pending);
+			return Err(ApiError::PartUploadTimeout{
+				part_number:first_pending as u32,
+			});
+		}
+		let _=tokio::time::timeout(remaining.min(PART_RECHECK_INTERVAL),wait_progress(&mut events,&channel,hashed_sid)).await;
+	}
+	let progress_key=progress_key(hashed_sid);
+	let mut keys:Vec<&String>=session.part_etag.iter().collect();
+	keys.push(&progress_key);
+	if let Err(e)=redis::cmd("DEL").arg(&keys).query_async::<()>(&mut redis).await{
+		tracing::warn!("{:?}",e);
+	}
+	Ok(etags.into_iter().flatten().collect())
+}
+/**
+ * このセッションのパートの進捗が配信されるまで待つ
+ */
+async fn wait_progress(events:&mut broadcast::Receiver<Arc<StreamEvent>>,channel:&str,hashed_sid:&str){
+	loop{
+		match events.recv().await{
+			Ok(event)=>{
+				let message=&event.message;
+				if event.channel==channel&&message["type"]=="uploadProgress"&&message["body"]["sessionId"]==hashed_sid{
+					return;
+				}
+			},
+			//取りこぼした中に該当するものがあったかもしれない
+			Err(broadcast::error::RecvError::Lagged(_))=>return,
+			//購読が止まっている場合は一定間隔の確認に任せる
+			Err(broadcast::error::RecvError::Closed)=>std::future::pending::<()>().await,
+		}
+	}
+}
+/**
+ * direct uploadで作られたオブジェクトのサイズ、種類、ハッシュを確認して(md5,sha256)を返す
+ * 種類はpreflightで申告されたものと一致する必要がある
+ */
+async fn verify_direct(ctx:&Context,session:&mut UploadSession,expected_md5:Option<&str>)->Result<(String,String),ApiError>{
+	use sha2::Digest;
+	let bucket=ctx.bucket.load();
+	let (head,_)=match bucket.head_object(&session.s3_key).await{
+		Ok(v)=>v,
+		Err(e)=>{
+			ctx.metrics.s3_error("head_object");
+			return Err(e.into());
+		}
+	};
+	if head.content_length!=Some(session.content_length as i64){
+		tracing::debug!("content_length mismatch {:?}!={}",head.content_length,session.content_length);
+		return Err(ApiError::InvalidParam("content_length mismatch".to_owned()));
+	}
+	let mut stream=match bucket.get_object_stream(&session.s3_key).await{
+		Ok(v)=>v,
+		Err(e)=>{
+			ctx.metrics.s3_error("get_object_stream");
+			return Err(e.into());
+		}
+	};
+	let mut md5sum=md5::Context::new();
+	let mut sha256sum=sha2::Sha256::new();
+	let mut head_buf=Vec::with_capacity(4096);
+	let mut read_length=0u64;
+	let body=stream.bytes();
+	while let Some(chunk)=body.try_next().await?{
+		if head_buf.len()<4096{
+			let len=chunk.len().min(4096-head_buf.len());
+			head_buf.extend_from_slice(&chunk[0..len]);
+		}
+		read_length+=chunk.len() as u64;
+		md5sum.consume(&chunk);
+		sha256sum.update(&chunk);
+	}
+	if read_length!=session.content_length{
+		return Err(ApiError::InvalidParam("content_length mismatch".to_owned()));
+	}
+	let (content_type,ext)=crate::browsersafe::detect(&head_buf);
+	if content_type!=session.content_type{
+		tracing::debug!("content_type mismatch {}!={}",content_type,session.content_type);
+		return Err(ApiError::InvalidParam("content_type mismatch".to_owned()));
+	}
+	session.ext=ext;
+	let md5sum=md5sum.compute().0;
+	let md5sum=md5sum.iter().map(|n| format!("{:02x}", n)).collect::<String>();
+	if let Some(expected_md5)=expected_md5{
+		if !expected_md5.eq_ignore_ascii_case(&md5sum){
+			return Err(ApiError::InvalidParam("md5 mismatch".to_owned()));
+		}
+	}
+	let sha256sum=sha256sum.finalize().iter().map(|n| format!("{:02x}", n)).collect::<String>();
+	Ok((md5sum,sha256sum))
+}
@@ -7,7 +7,7 @@ use serde::{Deserialize, Serialize};
 use tokio::io::AsyncReadExt;
 use tokio_util::io::StreamReader;
 
-use crate::Context;
+use crate::{error::ApiError, service::event::DriveEventType, Context, UploadSession};
 
 #[derive(Debug,Serialize, Deserialize)]
 pub struct RequestParams{
@@ -19,10 +19,13 @@ pub async fn post(
 	request: axum::extract::Request,
 )->axum::response::Response{
 	let authorization=request.headers().get("Authorization").cloned();
-	let _=match ctx.upload_session(authorization.as_ref(),false).await{
+	let (current,_)=match ctx.upload_session(authorization.as_ref(),false).await{
 		Ok(v)=>v,
-		Err(e)=>return e,
+		Err(e)=>return e.into_response(),
 	};
+	if current.direct{
+		return ApiError::InvalidParam("parts of direct upload session must be sent to upload_urls".to_owned()).into_response();
+	}
 	let body=request.into_body();
 	let body=body.into_data_stream();
 	let body_with_io_error = body.map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err));
@@ -38,108 +41,152 @@ pub async fn post(
 					if len==0{
 						break;
 					}
-					if all_body.len()+len>ctx.config.part_max_size as usize{
-						return (StatusCode::PAYLOAD_TOO_LARGE).into_response();
+					if all_body.len()+len>ctx.config.load().part_max_size as usize{
+						return ApiError::MaxFileSizeExceeded.into_response();
 					}
 					all_body.extend_from_slice(&buf[0..len]);
 				},
 				Err(e)=>{
-					eprintln!("{}:{} {:?}",file!(),line!(),e);
-					return (StatusCode::INTERNAL_SERVER_ERROR).into_response();
+					return ApiError::internal(e).into_response();
 				}
 			}
 		}
 		all_body
 	};
 	if buf.len()==0{
-		let mut header=axum::http::header::HeaderMap::new();
-		header.append("X-Error","No Body".parse().unwrap());
-		return (StatusCode::BAD_REQUEST,header).into_response();
+		return ApiError::InvalidParam("empty body".to_owned()).into_response();
 	}
 	let (mut session,hashed_sid)=match ctx.upload_session(authorization.as_ref(),true).await{
 		Ok(v)=>v,
-		Err(e)=>return e,
+		Err(e)=>return e.into_response(),
 	};
 	//bucket.abort_upload(key, upload_id)
 	if let Some(v)=session.part_number.as_mut(){
 		if *v+1 == parms.partnumber{
 			*v+=1;
 		}else{
-			return (StatusCode::BAD_REQUEST).into_response();
+			let e=ApiError::InvalidParam(format!("expected partnumber {}",*v+1));
+			return discard(&ctx,&session,&hashed_sid,e).await;
 		}
 	}else{
 		if parms.partnumber==0{
 			session.part_number=Some(0);
 		}else{
-			return (StatusCode::BAD_REQUEST).into_response();
+			return discard(&ctx,&session,&hashed_sid,ApiError::InvalidParam("expected partnumber 0".to_owned())).await;
 		}
 	}
+	let total=session.content_length+buf.len() as u64;
+	if session.file_size_limit.map(|limit|total>limit).unwrap_or(false){
+		return discard(&ctx,&session,&hashed_sid,ApiError::MaxFileSizeExceeded).await;
+	}
+	if session.declared_length.map(|len|total>len).unwrap_or(false){
+		return discard(&ctx,&session,&hashed_sid,ApiError::InvalidParam("content exceeds declared content_length".to_owned())).await;
+	}
+	//予約を超える場合は最新の空き容量で予約し直す、それ以外は期限の延長だけ
+	let session_ttl=ctx.config.load().session_ttl;
+	let reserve=total.max(session.reserved);
+	let free_space=if reserve>session.reserved{
+		match ctx.drive_service.free_space(&session.user_id).await{
+			Ok(free)=>Some(free),
+			Err(e)=>return discard(&ctx,&session,&hashed_sid,e.into()).await,
+		}
+	}else{
+		None
+	};
+	match ctx.quota_service.reserve(&session.user_id,&hashed_sid,reserve,free_space,session_ttl).await{
+		Ok(true)=>session.reserved=reserve,
+		Ok(false)=>return discard(&ctx,&session,&hashed_sid,ApiError::NoFreeSpace).await,
+		Err(e)=>return discard(&ctx,&session,&hashed_sid,e.into()).await,
+	}
 	if session.part_number==Some(0){
 		//最初のオブジェクト
-		let mut ext=None;
-		let mut content_type="";
-		if let Some(kind)=infer::get(&buf){
-			content_type=kind.mime_type();
-			ext=Some(format!(".{}",kind.extension()));
-			//println!("known content_type:{}",content_type);
-		}
-		if ext.as_ref().map(|s|s.as_str()) == Some("") {
-			ext=match content_type{
-				"image/jpeg"=>Some(".jpg"),
-				"image/png"=>Some(".png"),
-				"image/webp"=>Some(".webp"),
-				"image/avif"=>Some(".avif"),
-				"image/apng"=>Some(".apng"),
-				"image/vnd.mozilla.apng"=>Some(".apng"),
-				_=>None,
-			}.map(|s|s.to_owned());
-		}
-		if content_type == "image/apng"{
-			content_type="image/png";
-		}
-		if !crate::browsersafe::FILE_TYPE_BROWSERSAFE.contains(&content_type){
-			content_type = "application/octet-stream";
-			ext = None;
-		}
+		let (content_type,ext)=crate::browsersafe::detect(&buf);
 		session.content_type=content_type.to_owned();
 		session.ext=ext;
-		session.upload_id=match ctx.bucket.initiate_multipart_upload(&session.s3_key,content_type).await{
+		session.upload_id=match ctx.bucket.load().initiate_multipart_upload(&session.s3_key,content_type).await{
 			Ok(imur)=>{
 				Some(imur.upload_id)
 			},
 			Err(e)=>{
-				eprintln!("{}:{} {:?}",file!(),line!(),e);
-				return (StatusCode::INTERNAL_SERVER_ERROR).into_response();
+				ctx.metrics.s3_error("initiate_multipart_upload");
+				return discard(&ctx,&session,&hashed_sid,e.into()).await;
 			}
 		};
 	}
 	//let start_time=chrono::Utc::now();
 	let mut md5sum=crate::md5_ontext_from_raw(&session.md5_ctx_64);
 	if let Err(e)=md5sum.write_all(&buf){
-		eprintln!("{}:{} {:?}",file!(),line!(),e);
-		return (StatusCode::INTERNAL_SERVER_ERROR).into_response();
+		return discard(&ctx,&session,&hashed_sid,ApiError::internal(e)).await;
 	}
 	session.md5_ctx_64=crate::md5_ontext_into_raw(md5sum);
 	//println!("md5 {}ms",(chrono::Utc::now()-start_time).num_milliseconds());
 	session.content_length+=buf.len() as u64;
 	let temp_id=format!("s3_wait_etag:{}",uuid::Uuid::new_v4().to_string());
 	session.part_etag.push(temp_id.clone());
-	match ctx.redis.set_ex::<&String,String,()>(&format!("multipartUpload:{}",hashed_sid),serde_json::to_string(&session).unwrap(),ctx.config.session_ttl).await{
+	match ctx.redis.set_ex::<&String,String,()>(&format!("multipartUpload:{}",hashed_sid),serde_json::to_string(&session).unwrap(),session_ttl).await{
 		Ok(_)=>{},
-		_=>return StatusCode::INTERNAL_SERVER_ERROR.into_response(),
+		Err(e)=>return discard(&ctx,&session,&hashed_sid,e.into()).await,
 	}
 	let mut redis=ctx.redis.clone();
-	tokio::runtime::Handle::current().spawn(async move{
-		match ctx.bucket.put_multipart_chunk(buf,&session.s3_key,parms.partnumber+1,&session.upload_id.unwrap(),&session.content_type).await{
-			Ok(part)=>{
-				let _=redis.set_ex::<String,String,()>(temp_id,part.etag,24*60*60).await;//24時間後に失敗する
-			},
+	//終了時に完了を待てるように追跡する
+	let tasks=ctx.tasks.clone();
+	tasks.spawn(async move{
+		let part_length=buf.len() as u64;
+		let etag=match ctx.bucket.load().put_multipart_chunk(buf,&session.s3_key,parms.partnumber+1,session.upload_id.as_ref().unwrap(),&session.content_type).await{
+			Ok(part)=>part.etag,
 			Err(e)=>{
-				eprintln!("{}:{} {:?}",file!(),line!(),e);
+				tracing::error!("{:?}",e);
+				ctx.metrics.s3_error("put_multipart_chunk");
 				//空文字列は失敗
-				let _=redis.set_ex::<&str,&str,()>(temp_id.as_str(),"",24*60*60).await;//24時間後に失敗する
+				String::new()
+			}
+		};
+		let succeeded=!etag.is_empty();
+		let (parts,bytes)=if succeeded{
+			(1,part_length)
+		}else{
+			(0,0)
+		};
+		let progress_key=super::finish_upload::progress_key(&hashed_sid);
+		let res=redis::pipe().atomic()
+			.set_ex(&temp_id,etag,24*60*60).ignore()//24時間後に失敗する
+			.hincr(&progress_key,"parts",parts)
+			.hincr(&progress_key,"bytes",bytes)
+			.expire(&progress_key,24*60*60).ignore()
+			.query_async::<(u64,u64)>(&mut redis).await;
+		let (parts_committed,bytes_committed)=match res{
+			Ok(v)=>v,
+			Err(e)=>{
+				tracing::error!("{:?}",e);
+				return;
 			}
+		};
+		//finish-uploadで待っている場合はこのイベントで起こす
+		let progress=serde_json::json!({
+			"sessionId":hashed_sid,
+			"partNumber":parms.partnumber,
+			"succeeded":succeeded,
+			"bytesReceived":session.content_length,
+			"bytesCommitted":bytes_committed,
+			"partsCommitted":parts_committed,
+			"bytesTotal":session.declared_length,
+		});
+		if let Err(e)=ctx.event_service.publish_drive_stream(&session.user_id,Some(DriveEventType::UploadProgress),Some(progress)).await{
+			tracing::debug!("{:?}",e);
 		}
 	});
 	(StatusCode::NO_CONTENT).into_response()
 }
+/**
+ * 取り出したセッションを戻さずに終わる場合に、分割アップロードと容量の予約を破棄する
+ */
+async fn discard(ctx:&Context,session:&UploadSession,hashed_sid:&str,e:ApiError)->axum::response::Response{
+	if let Some(upload_id)=session.upload_id.as_ref(){
+		if ctx.bucket.load().abort_upload(&session.s3_key,upload_id).await.is_err(){
+			ctx.metrics.s3_error("abort_upload");
+		}
+	}
+	ctx.quota_service.release(&session.user_id,hashed_sid).await;
+	ctx.metrics.multipart_sessions.with_label_values(&["failed"]).inc();
+	e.into_response()
+}
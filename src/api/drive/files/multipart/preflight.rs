@@ -5,7 +5,7 @@ use serde::{Deserialize, Serialize};
 use tokio::io::AsyncReadExt;
 use tokio_util::io::StreamReader;
 
-use crate::{models::{access_token::MiAccessToken, user::MiUser}, Context, UploadSession};
+use crate::{error::ApiError, Context, UploadSession};
 
 #[derive(Debug, Deserialize)]
 pub struct RequestParams{
@@ -18,14 +18,35 @@ pub struct RequestParams{
 	is_sensitive:bool,
 	comment:Option<String>,
 	force:bool,
+	//trueならパートのアップロード先URLを返す、content_lengthとcontent_typeが必須
+	#[serde(default)]
+	direct:bool,
+	content_type:Option<String>,
+	//forceがfalseで同じハッシュのファイルが既にあれば、アップロードせずにそれを返す
+	md5:Option<String>,
+	sha256:Option<String>,
 }
 #[derive(Debug, Serialize)]
 pub struct ResponseBody{
 	allow_upload:bool,
 	min_split_size:u32,
 	max_split_size:u64,
-	session_id:String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	session_id:Option<String>,
+	//ドライブストリームのuploadProgress,fileProcessingイベントのsessionId
+	#[serde(skip_serializing_if = "Option::is_none")]
+	stream_session_id:Option<String>,
+	//既存のファイルを使う場合はallow_uploadがfalseでfileにそれが入る
+	#[serde(skip_serializing_if = "Option::is_none")]
+	file:Option<serde_json::Value>,
+	//directの場合のみ、最後以外のパートは全てpart_sizeで分割する
+	#[serde(skip_serializing_if = "Option::is_none")]
+	part_size:Option<u64>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	upload_urls:Option<Vec<String>>,
 }
+//S3の分割アップロードのパート数の上限
+const MAX_PARTS:u64=10000;
 pub async fn post(
 	mut ctx:Context,
 	request: axum::extract::Request,
@@ -36,65 +57,151 @@ pub async fn post(
 	let mut body_reader = StreamReader::new(body_with_io_error);
 	let mut buf=vec![];
 	if let Err(e)=body_reader.read_to_end(&mut buf).await{
-		eprintln!("{}:{} {:?}",file!(),line!(),e);
-		return (StatusCode::INTERNAL_SERVER_ERROR).into_response();
+		return ApiError::internal(e).into_response();
 	}
 	let q=match serde_json::from_slice::<RequestParams>(&buf){
 		Ok(v)=>v,
 		Err(e)=>{
-			eprintln!("{}:{} {:?}",file!(),line!(),e);
-			return (StatusCode::BAD_REQUEST).into_response();
+			tracing::debug!("{:?}",e);
+			return ApiError::InvalidParam(e.to_string()).into_response();
 		}
 	};
 	//let offset_time=chrono::Utc::now();
 
-	let user;
-	let mut register_preflight_result=Err(crate::service::drive::RegisterPreflightError::InternalServerError);
-	if let Some(mut con)=ctx.raw_db.get().await{
-		let db_token=MiAccessToken::load_by_id(&mut con, &q.i).await;
-		user=match db_token{
-			Some(token)=>MiUser::load_by_id(&mut con,&token.user_id).await,
-			None=>MiUser::load_by_token(&mut con,&q.i).await
-		};
-		if let Some(me)=user.as_ref(){
-			println!("call register_preflight");
-			register_preflight_result=ctx.drive_service.register_preflight(
-				Some(&me),
-				q.content_length.unwrap_or_default() as i64,
-				q.name.as_deref().unwrap_or_default(),
-				None,
-				false,
-				q.folder_id.as_deref(),
-			).await;
-		}else{
-			println!("not found MiUser");
+	let user=match crate::api::drive::files::create::authenticate(&ctx,Some(&q.i)).await{
+		Ok(user)=>user,
+		Err(e)=>return e.into_response(),
+	};
+	let me=&user;
+	crate::api::access_log::record_user(&me.id);
+	if !q.force&&(q.md5.is_some()||q.sha256.is_some()){
+		if let Some((_file,packed))=ctx.drive_service.find_duplicate(me,q.md5.as_deref(),q.sha256.as_deref(),q.is_sensitive).await{
+			let res=ResponseBody{
+				allow_upload:false,
+				min_split_size:min_size,
+				max_split_size:ctx.config.load().part_max_size,
+				session_id:None,
+				stream_session_id:None,
+				file:Some(packed.unwrap_or(serde_json::Value::Null)),
+				part_size:None,
+				upload_urls:None,
+			};
+			let mut header=axum::http::header::HeaderMap::new();
+			header.insert(axum::http::header::CONTENT_TYPE,"application/json".parse().unwrap());
+			return (StatusCode::OK,header,serde_json::to_string(&res).unwrap()).into_response();
 		}
-	}else{
-		let mut header=axum::http::header::HeaderMap::new();
-		header.insert("X-ErrorStatus","DB Pool".parse().unwrap());
-		return (axum::http::StatusCode::INTERNAL_SERVER_ERROR,header).into_response();
 	}
+	tracing::debug!("call register_preflight");
+	let register_preflight_result=ctx.drive_service.register_preflight(
+		Some(&me),
+		q.content_length.unwrap_or_default() as i64,
+		q.name.as_deref().unwrap_or_default(),
+		None,
+		false,
+		q.folder_id.as_deref(),
+	).await;
 	//println!("preflight{}ms",(chrono::Utc::now()-offset_time).num_milliseconds());
 	if let Err(e)=register_preflight_result{
-		let mut header=axum::http::header::HeaderMap::new();
-		header.insert("X-ErrorStatus",format!("{:?}",e).parse().unwrap());
-		return (axum::http::StatusCode::BAD_REQUEST,header).into_response();
+		return ApiError::from(e).into_response();
 	}
 	let backend_res=register_preflight_result.unwrap();
 	//println!("PREFLIGHT {:?}",res);
-	println!("content_length:{:?}",q.content_length);
+	tracing::debug!("content_length:{:?}",q.content_length);
+	let config=ctx.config.load();
+	let session_id=uuid::Uuid::new_v4().to_string();
 	let mut res=ResponseBody{
 		allow_upload:true,
 		min_split_size:min_size,
-		max_split_size:ctx.config.part_max_size,
-		session_id:uuid::Uuid::new_v4().to_string(),
+		max_split_size:config.part_max_size,
+		session_id:Some(session_id.clone()),
+		stream_session_id:None,
+		file:None,
+		part_size:None,
+		upload_urls:None,
 	};
-	let s3_key=format!("{}/{}",ctx.config.prefix,uuid::Uuid::new_v4().to_string());
+	let s3_key=format!("{}/{}",config.prefix,uuid::Uuid::new_v4().to_string());
 	//進行中の分割アップロードの一覧が取れる。これを使って適当に掃除する
 	//bucket.list_multiparts_uploads(Some("/"), Some("/"));
 	let md5_ctx_64=crate::md5_ontext_into_raw(md5::Context::new());
-	let session=UploadSession{
-		user_id:user.as_ref().unwrap().id.clone(),
+	let sid={
+		use sha2::{Sha256, Digest};
+		let mut hasher = Sha256::new();
+		hasher.update(session_id.as_bytes());
+		let hash=hasher.finalize();
+		use base64::Engine;
+		base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(hash)
+	};
+	res.stream_session_id=Some(sid.clone());
+	let session_ttl=if q.direct{
+		match config.direct_upload_ttl{
+			Some(ttl)=>ttl,
+			None=>return ApiError::InvalidParam("direct upload is disabled".to_owned()).into_response(),
+		}
+	}else{
+		config.session_ttl
+	};
+	//申告されたサイズを先に予約して、並行するセッションが同じ空き容量を使えないようにする
+	let declared_length=q.content_length.filter(|len|*len>0);
+	let reserved=declared_length.unwrap_or_default();
+	match ctx.quota_service.reserve(&me.id,&sid,reserved,backend_res.free_space,session_ttl).await{
+		Ok(true)=>{},
+		Ok(false)=>return ApiError::NoFreeSpace.into_response(),
+		Err(e)=>return ApiError::from(e).into_response(),
+	}
+	let mut direct=None;
+	if q.direct{
+		let content_length=match q.content_length{
+			Some(len) if len>0=>len,
+			_=>{
+				ctx.quota_service.release(&me.id,&sid).await;
+				return ApiError::InvalidParam("content_length is required for direct upload".to_owned()).into_response();
+			}
+		};
+		let content_type=match q.content_type.as_deref(){
+			Some(content_type)=>crate::browsersafe::normalize(content_type).to_owned(),
+			None=>{
+				ctx.quota_service.release(&me.id,&sid).await;
+				return ApiError::InvalidParam("content_type is required for direct upload".to_owned()).into_response();
+			}
+		};
+		let part_size=config.part_max_size;
+		let part_count=content_length.div_ceil(part_size);
+		if part_count>MAX_PARTS{
+			ctx.quota_service.release(&me.id,&sid).await;
+			return ApiError::InvalidParam("too many parts".to_owned()).into_response();
+		}
+		let bucket=ctx.bucket.load();
+		let upload_id=match bucket.initiate_multipart_upload(&s3_key,&content_type).await{
+			Ok(imur)=>imur.upload_id,
+			Err(e)=>{
+				ctx.metrics.s3_error("initiate_multipart_upload");
+				ctx.quota_service.release(&me.id,&sid).await;
+				return ApiError::from(e).into_response();
+			}
+		};
+		let mut upload_urls=vec![];
+		for part_number in 1..=part_count{
+			let mut queries=std::collections::HashMap::new();
+			queries.insert("partNumber".to_owned(),part_number.to_string());
+			queries.insert("uploadId".to_owned(),upload_id.clone());
+			match bucket.presign_put(&s3_key,session_ttl as u32,None,Some(queries)).await{
+				Ok(url)=>upload_urls.push(url),
+				Err(e)=>{
+					ctx.metrics.s3_error("presign_put");
+					if bucket.abort_upload(&s3_key,&upload_id).await.is_err(){
+						ctx.metrics.s3_error("abort_upload");
+					}
+					ctx.quota_service.release(&me.id,&sid).await;
+					return ApiError::from(e).into_response();
+				}
+			}
+		}
+		res.part_size=Some(part_size);
+		res.upload_urls=Some(upload_urls);
+		direct=Some((upload_id,content_length,content_type,part_count as u32));
+	}
+	let mut session=UploadSession{
+		user_id:me.id.clone(),
 		s3_key,
 		part_number:None,
 		content_length:0,
@@ -110,23 +217,27 @@ pub async fn post(
 		force:q.force,
 		sensitive_threshold:backend_res.sensitive_threshold,
 		skip_sensitive_detection:backend_res.skip_sensitive_detection,
+		direct:false,
+		declared_length,
+		file_size_limit:backend_res.file_size_limit.map(|v|v.max(0) as u64),
+		reserved,
 	};
+	if let Some((upload_id,content_length,content_type,part_count))=direct{
+		//パートの順番はfinish-uploadで受け取るETagの順で決まる
+		session.direct=true;
+		session.upload_id=Some(upload_id);
+		session.content_length=content_length;
+		session.content_type=content_type;
+		session.part_number=Some(part_count-1);
+	}
 	let session=serde_json::to_string(&session).unwrap();
-	let sid={
-		use sha2::{Sha256, Digest};
-		let mut hasher = Sha256::new();
-		hasher.update(res.session_id.as_bytes());
-		let hash=hasher.finalize();
-		use base64::Engine;
-		base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(hash)
-	};
 	let mut header=axum::http::header::HeaderMap::new();
 	header.insert(axum::http::header::CONTENT_TYPE,"application/json".parse().unwrap());
-	if let Err(e)=ctx.redis.set_ex::<&String,String,()>(&format!("multipartUpload:{}",sid),session,ctx.config.session_ttl).await{
-		eprintln!("{}:{} {:?}",file!(),line!(),e);
-		res.allow_upload=false;
-		(StatusCode::INTERNAL_SERVER_ERROR,header,serde_json::to_string(&res).unwrap()).into_response()
+	if let Err(e)=ctx.redis.set_ex::<&String,String,()>(&format!("multipartUpload:{}",sid),session,session_ttl).await{
+		ctx.quota_service.release(&me.id,&sid).await;
+		ApiError::from(e).into_response()
 	}else{
+		ctx.metrics.multipart_sessions.with_label_values(&["started"]).inc();
 		(StatusCode::OK,header,serde_json::to_string(&res).unwrap()).into_response()
 	}
 }
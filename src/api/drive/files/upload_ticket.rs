@@ -0,0 +1,207 @@
+use axum::response::IntoResponse;
+use futures::TryStreamExt;
+use redis::AsyncCommands;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncReadExt;
+use tokio_util::io::StreamReader;
+
+use crate::{error::ApiError, models::user::MiUser, Context};
+
+use super::create::RequestParms;
+
+//有効期限を省略した場合の秒数
+const DEFAULT_TICKET_TTL:u64=60*60;
+#[derive(Debug, Deserialize)]
+pub struct RequestParams{
+	i: String,//トークン必須
+	#[serde(rename = "folderId")]
+	folder_id:Option<String>,
+	#[serde(rename = "maxSize")]
+	max_size:Option<u64>,
+	//"image/*"のように主タイプだけを指定することもできる
+	#[serde(rename = "allowedTypes")]
+	allowed_types:Option<Vec<String>>,
+	#[serde(rename = "isSensitive",default)]
+	is_sensitive:bool,
+	comment:Option<String>,
+	//有効期限(秒)、設定のupload_ticket_max_ttlが上限
+	#[serde(rename = "expiresIn")]
+	expires_in:Option<u64>,
+}
+#[derive(Debug, Serialize)]
+pub struct ResponseBody{
+	ticket:String,
+	url:String,
+	#[serde(rename = "expiresAt")]
+	expires_at:String,
+}
+/**
+ * Redisに保存するチケットの内容
+ * チケット自体はハッシュ化したものをキーにする
+ */
+#[derive(Debug, Serialize, Deserialize)]
+struct UploadTicket{
+	user_id:String,
+	folder_id:Option<String>,
+	max_size:Option<u64>,
+	allowed_types:Option<Vec<String>>,
+	is_sensitive:bool,
+	comment:Option<String>,
+}
+fn ticket_key(ticket:&str)->String{
+	use sha2::{Sha256, Digest};
+	let mut hasher = Sha256::new();
+	hasher.update(ticket.as_bytes());
+	let hash=hasher.finalize();
+	use base64::Engine;
+	format!("uploadTicket:{}",base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(hash))
+}
+/**
+ * ユーザーとフォルダ、サイズ、種類を固定した1回限りのアップロード用URLを発行する
+ */
+pub async fn post(
+	mut ctx:Context,
+	request: axum::extract::Request,
+)->axum::response::Response{
+	let max_ttl=match ctx.config.load().upload_ticket_max_ttl{
+		Some(ttl)=>ttl,
+		None=>return ApiError::InvalidParam("upload tickets are disabled".to_owned()).into_response(),
+	};
+	let stream=request.into_body().into_data_stream();
+	let body_with_io_error = stream.map_err(|err| std::io::Error::new(std::io::ErrorKind::Other, err));
+	let mut body_reader = StreamReader::new(body_with_io_error);
+	let mut buf=vec![];
+	if let Err(e)=body_reader.read_to_end(&mut buf).await{
+		return ApiError::internal(e).into_response();
+	}
+	let q=match serde_json::from_slice::<RequestParams>(&buf){
+		Ok(v)=>v,
+		Err(e)=>{
+			tracing::debug!("{:?}",e);
+			return ApiError::InvalidParam(e.to_string()).into_response();
+		}
+	};
+	let me=match super::create::authenticate(&ctx,Some(&q.i)).await{
+		Ok(me)=>me,
+		Err(e)=>return e.into_response(),
+	};
+	crate::api::access_log::record_user(&me.id);
+	if q.folder_id.is_some(){
+		let mut con=match ctx.raw_db.get().await{
+			Some(con)=>con,
+			None=>return ApiError::internal("DB Pool").into_response(),
+		};
+		let folder=crate::service::drive::fetch_folder(&mut con,q.folder_id.as_deref(),Some(&me.id)).await;
+		if folder.is_none(){
+			return ApiError::NoSuchFolder.into_response();
+		}
+	}
+	let ttl=q.expires_in.unwrap_or(DEFAULT_TICKET_TTL).min(max_ttl);
+	if ttl==0{
+		return ApiError::InvalidParam("expiresIn must be greater than 0".to_owned()).into_response();
+	}
+	let ticket=UploadTicket{
+		user_id:me.id,
+		folder_id:q.folder_id,
+		max_size:q.max_size,
+		allowed_types:q.allowed_types,
+		is_sensitive:q.is_sensitive,
+		comment:q.comment,
+	};
+	let ticket_id={
+		use base64::Engine;
+		let mut raw=uuid::Uuid::new_v4().as_bytes().to_vec();
+		raw.extend_from_slice(uuid::Uuid::new_v4().as_bytes());
+		base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(raw)
+	};
+	if let Err(e)=ctx.redis.set_ex::<String,String,()>(ticket_key(&ticket_id),serde_json::to_string(&ticket).unwrap(),ttl).await{
+		return ApiError::from(e).into_response();
+	}
+	let res=ResponseBody{
+		url:format!("{}/api/drive/files/upload-tickets/{}",ctx.misskey_config.load().url.trim_end_matches('/'),ticket_id),
+		ticket:ticket_id,
+		expires_at:(chrono::Utc::now()+chrono::Duration::seconds(ttl as i64)).to_rfc3339(),
+	};
+	let mut header=axum::http::header::HeaderMap::new();
+	header.insert(axum::http::header::CONTENT_TYPE,"application/json".parse().unwrap());
+	(axum::http::StatusCode::OK,header,serde_json::to_string(&res).unwrap()).into_response()
+}
+/**
+ * チケットのURLへのアップロード
+ * 本文はcreateと同じmultipart/form-dataで、iの代わりにチケットで認証する
+ * チケットは受け取った時点で消費するので、失敗した場合も再利用できない
+ */
+pub async fn upload(
+	mut ctx:Context,
+	axum::extract::Path(ticket_id):axum::extract::Path<String>,
+	mut multipart: axum::extract::Multipart,
+)->axum::response::Response{
+	let ticket=match ctx.redis.get_del::<String,Option<String>>(ticket_key(&ticket_id)).await{
+		Ok(Some(ticket))=>ticket,
+		Ok(None)=>return ApiError::AuthenticationFailed.into_response(),
+		Err(e)=>return ApiError::from(e).into_response(),
+	};
+	let ticket=match serde_json::from_str::<UploadTicket>(&ticket){
+		Ok(ticket)=>ticket,
+		Err(e)=>return ApiError::internal(e).into_response(),
+	};
+	let mut con=match ctx.raw_db.get().await{
+		Some(con)=>con,
+		None=>return ApiError::internal("DB Pool").into_response(),
+	};
+	let me=match MiUser::load_by_id(&mut con,&ticket.user_id).await{
+		Some(me)=>me,
+		None=>return ApiError::AuthenticationFailed.into_response(),
+	};
+	drop(con);
+	crate::api::access_log::record_user(&me.id);
+	let mut req=RequestParms{
+		comment:ticket.comment,
+		is_sensitive:ticket.is_sensitive,
+		folder_id:ticket.folder_id,
+		//チケットで既存のファイルを返すと発行者以外にファイルの情報が渡るので、重複は確認しない
+		force:true,
+		..Default::default()
+	};
+	let mut file_data=None;
+	while let Some(mut field) = multipart.next_field().await.unwrap_or(None) {
+		let name = match field.name(){
+			Some(name)=>name.to_owned(),
+			None=>continue,
+		};
+		if &name=="file"{
+			//最大サイズを超えた時点で受信をやめる
+			let mut data=vec![];
+			loop{
+				match field.chunk().await{
+					Ok(Some(chunk))=>{
+						if ticket.max_size.map(|max|(data.len()+chunk.len()) as u64>max).unwrap_or(false){
+							return ApiError::MaxFileSizeExceeded.into_response();
+						}
+						data.extend_from_slice(&chunk);
+					},
+					Ok(None)=>break,
+					Err(e)=>return ApiError::InvalidParam(e.to_string()).into_response(),
+				}
+			}
+			file_data=Some(axum::body::Bytes::from(data));
+			continue;
+		}
+		let data=match field.bytes().await{
+			Ok(data)=>data,
+			Err(_)=>continue,
+		};
+		if &name=="name"{
+			req.name=String::from_utf8(data.to_vec()).ok();
+		}
+		if &name=="comment"&&req.comment.is_none(){
+			req.comment=String::from_utf8(data.to_vec()).ok();
+		}
+	}
+	let file_data=match file_data{
+		Some(file_data)=>file_data,
+		None=>return ApiError::FileRequired.into_response(),
+	};
+	req.size=file_data.len() as u64;
+	super::create::store(&ctx,&me,req,file_data,ticket.allowed_types.as_deref()).await
+}
@@ -0,0 +1,96 @@
+use axum::{http::{HeaderMap, StatusCode}, response::IntoResponse, Router};
+
+use crate::Context;
+
+//ストレージからそのまま中継するヘッダ
+const FORWARD_REQUEST_HEADERS:[&str;6]=[
+	"range",
+	"if-range",
+	"if-match",
+	"if-none-match",
+	"if-modified-since",
+	"if-unmodified-since",
+];
+const FORWARD_RESPONSE_HEADERS:[&str;9]=[
+	"content-type",
+	"content-length",
+	"content-range",
+	"content-disposition",
+	"accept-ranges",
+	"cache-control",
+	"etag",
+	"last-modified",
+	"expires",
+];
+
+pub fn route(ctx: &Context,app: Router)->Router{
+	//public_base_url+access_keyがファイルのURLになる
+	let base_path=match reqwest::Url::parse(&ctx.config.load().public_base_url){
+		Ok(url)=>url.path().to_owned(),
+		Err(e)=>{
+			tracing::error!("{:?}",e);
+			return app;
+		}
+	};
+	let base_path=if base_path.ends_with("/"){
+		base_path
+	}else{
+		format!("{}/",base_path)
+	};
+	let ctx0=ctx.clone();
+	app.route(&format!("{}{}/*key",base_path,ctx.config.load().prefix),axum::routing::get(move|key,request|get(ctx0.clone(),key,request)))
+}
+pub async fn get(
+	ctx:Context,
+	axum::extract::Path(key):axum::extract::Path<String>,
+	request: axum::extract::Request,
+)->axum::response::Response{
+	let access_key=format!("{}/{}",ctx.config.load().prefix,key);
+	//署名付きURLの有効期限は転送開始までの間だけあれば良い
+	let url=match ctx.bucket.load().presign_get(&access_key,60,None).await{
+		Ok(url)=>url,
+		Err(e)=>{
+			tracing::error!("{:?}",e);
+			return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+		}
+	};
+	let mut builder=ctx.client.get(url);
+	for (k,v) in request.headers(){
+		if FORWARD_REQUEST_HEADERS.contains(&k.as_str()){
+			builder=builder.header(k,v);
+		}
+	}
+	let res=match builder.send().await{
+		Ok(res)=>res,
+		Err(e)=>{
+			tracing::error!("{:?}",e);
+			ctx.metrics.s3_error("get_object");
+			return StatusCode::BAD_GATEWAY.into_response();
+		}
+	};
+	let status=match res.status().as_u16(){
+		//Range,If-None-Match,If-Modified-Sinceの判定はストレージに任せる
+		200|206|304|412|416=>StatusCode::from_u16(res.status().as_u16()).unwrap_or(StatusCode::BAD_GATEWAY),
+		403|404=>return StatusCode::NOT_FOUND.into_response(),
+		status=>{
+			tracing::error!("storage status {}",status);
+			ctx.metrics.s3_error("get_object");
+			return StatusCode::BAD_GATEWAY.into_response();
+		}
+	};
+	let mut header=HeaderMap::new();
+	for (k,v) in res.headers(){
+		if FORWARD_RESPONSE_HEADERS.contains(&k.as_str()){
+			header.append(k,v.clone());
+		}
+	}
+	if !header.contains_key(axum::http::header::CACHE_CONTROL){
+		header.insert(axum::http::header::CACHE_CONTROL,"max-age=31536000, immutable".parse().unwrap());
+	}
+	if !header.contains_key(axum::http::header::ACCEPT_RANGES){
+		header.insert(axum::http::header::ACCEPT_RANGES,"bytes".parse().unwrap());
+	}
+	header.insert(axum::http::header::CONTENT_SECURITY_POLICY,"default-src 'none'; img-src 'self'; media-src 'self'; style-src 'unsafe-inline'".parse().unwrap());
+	let body=axum::body::Body::from_stream(res.bytes_stream());
+	(status,header,body).into_response()
+}
@@ -0,0 +1,109 @@
+use std::{collections::BTreeMap, time::Duration};
+
+use axum::{http::StatusCode, response::IntoResponse, Router};
+use serde::Serialize;
+
+use crate::Context;
+
+//各項目の確認にかける時間の上限
+const CHECK_TIMEOUT:Duration=Duration::from_secs(3);
+
+#[derive(Debug, Serialize)]
+struct ComponentStatus{
+	status:&'static str,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	error:Option<String>,
+	latency_ms:u64,
+}
+#[derive(Debug, Serialize)]
+struct ReadinessBody{
+	status:&'static str,
+	database:ComponentStatus,
+	redis:ComponentStatus,
+	storage:ComponentStatus,
+	//バックエンドの名前毎
+	backends:BTreeMap<String,ComponentStatus>,
+}
+pub fn route(ctx: &Context,app: Router)->Router{
+	let app=app.route("/healthz",axum::routing::get(healthz));
+	let ctx0=ctx.clone();
+	app.route("/readyz",axum::routing::get(move||readyz(ctx0.clone())))
+}
+/**
+ * プロセスが応答できるかだけを返す
+ */
+pub async fn healthz()->axum::response::Response{
+	(StatusCode::OK,"OK").into_response()
+}
+async fn check<F>(f:F)->ComponentStatus where F:std::future::Future<Output=Result<(),String>>{
+	let start=std::time::Instant::now();
+	let res=match tokio::time::timeout(CHECK_TIMEOUT,f).await{
+		Ok(res)=>res,
+		Err(_)=>Err("timeout".to_owned()),
+	};
+	let latency_ms=start.elapsed().as_millis() as u64;
+	match res{
+		Ok(_)=>ComponentStatus{
+			status:"ok",
+			error:None,
+			latency_ms,
+		},
+		Err(e)=>ComponentStatus{
+			status:"fail",
+			error:Some(e),
+			latency_ms,
+		},
+	}
+}
+async fn check_database(ctx:&Context)->Result<(),String>{
+	use diesel_async::RunQueryDsl;
+	let mut con=ctx.raw_db.get().await.ok_or("no connection from pool".to_owned())?;
+	diesel::sql_query("SELECT 1").execute(&mut con).await.map_err(|e|e.to_string())?;
+	Ok(())
+}
+async fn check_redis(ctx:&Context)->Result<(),String>{
+	let mut redis=ctx.redis.clone();
+	redis::cmd("PING").query_async::<String>(&mut redis).await.map_err(|e|e.to_string())?;
+	Ok(())
+}
+async fn check_storage(ctx:&Context)->Result<(),String>{
+	let key=format!("{}/{}",ctx.config.load().prefix,ctx.config.load().health_check_key.as_deref().unwrap_or(".healthcheck"));
+	match ctx.bucket.load().head_object(&key).await{
+		Ok(_)=>Ok(()),
+		//番兵が無くてもバケットに到達できていれば良い
+		Err(s3::error::S3Error::HttpFailWithBody(404,_))=>Ok(()),
+		Err(e)=>Err(e.to_string()),
+	}
+}
+pub async fn readyz(
+	ctx:Context,
+)->axum::response::Response{
+	let probes=ctx.upstream_service.load().probes(&ctx.client).into_iter().map(|(name,probe)|async move{
+		(name,check(probe).await)
+	});
+	let (database,redis,storage,backends)=futures::join!(
+		check(check_database(&ctx)),
+		check(check_redis(&ctx)),
+		check(check_storage(&ctx)),
+		futures::future::join_all(probes),
+	);
+	let backends:BTreeMap<String,ComponentStatus>=backends.into_iter().collect();
+	//振り分け先が一つでも応答すればリクエストを処理できる
+	let ready=[&database,&redis,&storage].iter().all(|c|c.status=="ok")&&backends.values().any(|c|c.status=="ok");
+	let body=ReadinessBody{
+		status:if ready{"ok"}else{"fail"},
+		database,
+		redis,
+		storage,
+		backends,
+	};
+	let status=if ready{
+		StatusCode::OK
+	}else{
+		StatusCode::SERVICE_UNAVAILABLE
+	};
+	let mut header=axum::http::HeaderMap::new();
+	header.insert(axum::http::header::CONTENT_TYPE,"application/json".parse().unwrap());
+	header.insert(axum::http::header::CACHE_CONTROL,"no-store".parse().unwrap());
+	(status,header,serde_json::to_string(&body).unwrap_or_default()).into_response()
+}
@@ -0,0 +1,27 @@
+use axum::Router;
+
+use crate::Context;
+
+mod media;
+
+/**
+ * Mastodon互換APIのうちアップロードを伴うもの
+ * それ以外のメソッドはバックエンドに転送する
+ */
+pub fn route(ctx: &Context,app: Router)->Router{
+	let ctx0=ctx.clone();
+	let ctx1=ctx.clone();
+	let media_app=Router::new().route("/api/v1/media",axum::routing::post(move|headers,multipart|media::post_v1(ctx0.clone(),headers,multipart)).fallback(move|addr,req|crate::api::default_route::proxy(ctx1.clone(),addr,req)));
+	let ctx0=ctx.clone();
+	let ctx1=ctx.clone();
+	let media_app=media_app.route("/api/v2/media",axum::routing::post(move|headers,multipart|media::post_v2(ctx0.clone(),headers,multipart)).fallback(move|addr,req|crate::api::default_route::proxy(ctx1.clone(),addr,req)));
+	let ctx0=ctx.clone();
+	let ctx1=ctx.clone();
+	let ctx2=ctx.clone();
+	let media_app=media_app.route("/api/v1/media/:id",axum::routing::get(move|headers,id|media::get(ctx0.clone(),headers,id)).put(move|headers,id,req|media::put(ctx1.clone(),headers,id,req)).fallback(move|addr,req|crate::api::default_route::proxy(ctx2.clone(),addr,req)));
+	let ctx0=ctx.clone();
+	let ctx1=ctx.clone();
+	let media_app=media_app.layer(axum::middleware::from_fn(move|req,next|crate::api::drive::files::body_limit(ctx0.clone(),req,next)))
+		.route_layer(axum::middleware::from_fn(move|req,next|crate::api::metrics::track_upload(ctx1.clone(),req,next)));
+	app.merge(media_app)
+}
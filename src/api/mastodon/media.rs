@@ -0,0 +1,382 @@
+use axum::{extract::{FromRequest, Multipart}, http::{HeaderMap, StatusCode}, response::IntoResponse};
+use redis::AsyncCommands;
+use serde::Deserialize;
+
+use crate::{api::drive::files::create::{self, RequestParms}, error::ApiError, models::user::MiUser, service::{drive::RegisterPreflightResult, file_meta::THUMBNAIL_SIZE}, Context};
+
+//非同期で処理中のメディアの状態を保持する秒数
+const PENDING_TTL:u64=60*60;
+//Mastodonは1500文字まで受け付けるが、保存先のcommentはMisskeyのDB_MAX_IMAGE_COMMENT_LENGTHまで
+const MAX_DESCRIPTION_LENGTH:usize=512;
+//処理中なら説明を記録して{1,種類}を返す、他人のものなら{-1,""}、処理中でなければ{0,""}
+const SET_PENDING_DESCRIPTION_SCRIPT:&str=r#"
+if redis.call('HGET',KEYS[1],'state')~='processing' then
+	return {0,''}
+end
+if redis.call('HGET',KEYS[1],'user_id')~=ARGV[2] then
+	return {-1,''}
+end
+redis.call('HSET',KEYS[1],'description',ARGV[1])
+return {1,redis.call('HGET',KEYS[1],'type') or ''}
+"#;
+
+#[derive(Debug, Deserialize)]
+pub struct UpdateParams{
+	description:Option<String>,
+}
+/**
+ * Mastodonの形式のエラーのステータスとメッセージ
+ */
+fn error_parts(e:&ApiError)->(StatusCode,String){
+	let status=match e.status(){
+		StatusCode::BAD_REQUEST=>StatusCode::UNPROCESSABLE_ENTITY,
+		status=>status,
+	};
+	let message=match e{
+		ApiError::InvalidParam(reason)=>format!("{} {}",e.message(),reason),
+		_=>e.message().to_owned(),
+	};
+	(status,message)
+}
+fn error_response(e:ApiError)->axum::response::Response{
+	let (status,message)=error_parts(&e);
+	json_response(status,serde_json::json!({"error":message}))
+}
+fn json_response(status:StatusCode,body:serde_json::Value)->axum::response::Response{
+	let mut header=HeaderMap::new();
+	header.insert(axum::http::header::CONTENT_TYPE,"application/json".parse().unwrap());
+	(status,header,body.to_string()).into_response()
+}
+async fn authenticate(ctx:&Context,headers:&HeaderMap)->Result<MiUser,ApiError>{
+	let token=match headers.get(axum::http::header::AUTHORIZATION).and_then(|v|v.to_str().ok()).and_then(|v|v.strip_prefix("Bearer ")){
+		Some(token)=>token,
+		None=>return Err(ApiError::CredentialRequired),
+	};
+	let me=ctx.user_service.authenticate(token).await.ok_or(ApiError::AuthenticationFailed)?;
+	crate::api::access_log::record_user(&me.id);
+	Ok(me)
+}
+fn pending_key(file_id:&str)->String{
+	format!("mastodonMedia:{}",file_id)
+}
+fn media_type(content_type:&str)->&'static str{
+	match content_type.split('/').next(){
+		Some("image")=>"image",
+		Some("video")=>"video",
+		Some("audio")=>"audio",
+		_=>"unknown",
+	}
+}
+fn validate_description(description:Option<&str>)->Result<(),ApiError>{
+	match description{
+		Some(description) if description.chars().count()>MAX_DESCRIPTION_LENGTH=>{
+			Err(ApiError::InvalidParam(format!("description is too long (maximum is {} characters)",MAX_DESCRIPTION_LENGTH)))
+		},
+		_=>Ok(()),
+	}
+}
+fn meta_size(width:u64,height:u64)->serde_json::Value{
+	serde_json::json!({
+		"width":width,
+		"height":height,
+		"size":format!("{}x{}",width,height),
+		"aspect":width as f64/height as f64,
+	})
+}
+/**
+ * packしたドライブのファイルをMediaAttachmentに変換する
+ * smallはサムネイルの大きさで、縦横比を保ってTHUMBNAIL_SIZEに収めたもの
+ */
+fn attachment(packed:&serde_json::Value)->serde_json::Value{
+	let content_type=packed["type"].as_str().unwrap_or_default();
+	let mut meta=serde_json::Map::new();
+	let width=packed["properties"]["width"].as_u64().unwrap_or_default();
+	let height=packed["properties"]["height"].as_u64().unwrap_or_default();
+	if width>0&&height>0{
+		meta.insert("original".into(),meta_size(width,height));
+		if packed["thumbnailUrl"].is_string(){
+			let scale=(THUMBNAIL_SIZE as f64/width.max(height) as f64).min(1.0);
+			let small_width=((width as f64*scale).round() as u64).max(1);
+			let small_height=((height as f64*scale).round() as u64).max(1);
+			meta.insert("small".into(),meta_size(small_width,small_height));
+		}
+	}
+	let preview_url=match &packed["thumbnailUrl"]{
+		serde_json::Value::Null=>packed["url"].clone(),
+		url=>url.clone(),
+	};
+	serde_json::json!({
+		"id":packed["id"],
+		"type":media_type(content_type),
+		"url":packed["url"],
+		"preview_url":preview_url,
+		"remote_url":null,
+		"text_url":null,
+		"meta":meta,
+		"description":packed["comment"],
+		"blurhash":packed["blurhash"],
+	})
+}
+/**
+ * 処理中のMediaAttachment、urlはnullになる
+ */
+fn pending_attachment(file_id:&str,content_type:&str,description:Option<&str>)->serde_json::Value{
+	serde_json::json!({
+		"id":file_id,
+		"type":media_type(content_type),
+		"url":null,
+		"preview_url":null,
+		"remote_url":null,
+		"text_url":null,
+		"meta":{},
+		"description":description,
+		"blurhash":null,
+	})
+}
+/**
+ * fileとdescriptionを受け取る、focusとthumbnailは保存先が無いので無視する
+ */
+async fn read_multipart(mut multipart:Multipart)->Result<(RequestParms,axum::body::Bytes),ApiError>{
+	let mut req=RequestParms{
+		//Mastodonでは同じ内容でも別のメディアになる
+		force:true,
+		..Default::default()
+	};
+	let mut file_data=None;
+	while let Some(field) = multipart.next_field().await.map_err(|e|ApiError::InvalidParam(e.to_string()))? {
+		let name = match field.name(){
+			Some(name)=>name.to_owned(),
+			None=>continue,
+		};
+		let file_name=field.file_name().map(|s|s.to_owned());
+		let data=field.bytes().await.map_err(|e|ApiError::InvalidParam(e.to_string()))?;
+		match name.as_str(){
+			"file"=>{
+				req.name=file_name;
+				file_data=Some(data);
+			},
+			"description"=>req.comment=String::from_utf8(data.to_vec()).ok().filter(|s|!s.is_empty()),
+			_=>{},
+		}
+	}
+	validate_description(req.comment.as_deref())?;
+	let file_data=file_data.ok_or(ApiError::FileRequired)?;
+	req.size=file_data.len() as u64;
+	Ok((req,file_data))
+}
+async fn register_preflight(ctx:&Context,me:&MiUser,req:&RequestParms)->Result<RegisterPreflightResult,ApiError>{
+	Ok(ctx.drive_service.register_preflight(
+		Some(me),
+		req.size as i64,
+		req.name.as_deref().unwrap_or_default(),
+		req.ext.as_deref(),
+		false,
+		None,
+	).await?)
+}
+async fn save(ctx:&Context,me:&MiUser,req:&RequestParms,file_data:axum::body::Bytes,content_type:&str,res:RegisterPreflightResult,file_id:Option<String>)->Result<serde_json::Value,ApiError>{
+	let packed=create::save(ctx,me,req,file_data,content_type,res,file_id).await?;
+	packed.ok_or_else(||ApiError::internal("pack"))
+}
+async fn process(ctx:&Context,me:&MiUser,req:&RequestParms,file_data:axum::body::Bytes,content_type:&str)->Result<serde_json::Value,ApiError>{
+	let res=register_preflight(ctx,me,req).await?;
+	save(ctx,me,req,file_data,content_type,res,None).await
+}
+/**
+ * POST /api/v1/media
+ * 処理が終わるまで待って返す
+ */
+pub async fn post_v1(
+	ctx:Context,
+	headers:HeaderMap,
+	multipart: Multipart,
+)->axum::response::Response{
+	let me=match authenticate(&ctx,&headers).await{
+		Ok(me)=>me,
+		Err(e)=>return error_response(e),
+	};
+	let (mut req,file_data)=match read_multipart(multipart).await{
+		Ok(v)=>v,
+		Err(e)=>return error_response(e),
+	};
+	let content_type=create::detect_content_type(&file_data,&mut req);
+	match process(&ctx,&me,&req,file_data,content_type).await{
+		Ok(packed)=>json_response(StatusCode::OK,attachment(&packed)),
+		Err(e)=>error_response(e),
+	}
+}
+/**
+ * POST /api/v2/media
+ * 画像はその場で処理して200、それ以外はIDを先に決めて202を返し、GETで完了を確認させる
+ * 容量などの確認は202を返す前に済ませ、処理が終わるまで容量を予約しておく
+ */
+pub async fn post_v2(
+	mut ctx:Context,
+	headers:HeaderMap,
+	multipart: Multipart,
+)->axum::response::Response{
+	let me=match authenticate(&ctx,&headers).await{
+		Ok(me)=>me,
+		Err(e)=>return error_response(e),
+	};
+	let (mut req,file_data)=match read_multipart(multipart).await{
+		Ok(v)=>v,
+		Err(e)=>return error_response(e),
+	};
+	let content_type=create::detect_content_type(&file_data,&mut req);
+	if content_type.starts_with("image/"){
+		return match process(&ctx,&me,&req,file_data,content_type).await{
+			Ok(packed)=>json_response(StatusCode::OK,attachment(&packed)),
+			Err(e)=>error_response(e),
+		};
+	}
+	let preflight=match register_preflight(&ctx,&me,&req).await{
+		Ok(res)=>res,
+		Err(e)=>return error_response(e),
+	};
+	let file_id=ctx.drive_service.gen_file_id();
+	match ctx.quota_service.reserve(&me.id,&file_id,req.size,preflight.free_space,PENDING_TTL).await{
+		Ok(true)=>{},
+		Ok(false)=>return error_response(ApiError::NoFreeSpace),
+		Err(e)=>return error_response(e.into()),
+	}
+	let reservation=ctx.quota_service.guard(&me.id,&file_id);
+	let key=pending_key(&file_id);
+	let mut pending=vec![
+		("state","processing".to_owned()),
+		("user_id",me.id.clone()),
+		("type",content_type.to_owned()),
+	];
+	if let Some(description)=req.comment.as_ref(){
+		pending.push(("description",description.clone()));
+	}
+	let res=redis::pipe().atomic()
+		.hset_multiple(&key,&pending).ignore()
+		.expire(&key,PENDING_TTL as i64).ignore()
+		.query_async::<()>(&mut ctx.redis).await;
+	if let Err(e)=res{
+		return error_response(e.into());
+	}
+	let body=pending_attachment(&file_id,content_type,req.comment.as_deref());
+	let tasks=ctx.tasks.clone();
+	tasks.spawn(async move{
+		//登録されれば使用量に含まれるので、結果に関わらず終了時に解放する
+		let _reservation=reservation;
+		let res=save(&ctx,&me,&req,file_data,content_type,preflight,Some(file_id.clone())).await;
+		let mut redis=ctx.redis.clone();
+		match res{
+			Ok(_)=>{
+				//処理中にPUTで変更された説明を反映する
+				let description=redis::pipe().atomic()
+					.hset(&key,"state","done").ignore()
+					.hget(&key,"description")
+					.query_async::<(Option<String>,)>(&mut redis).await;
+				match description{
+					Ok((description,))=>{
+						let description=description.filter(|s|!s.is_empty());
+						if description!=req.comment{
+							if ctx.drive_service.update_comment(&me,&file_id,description.as_deref()).await.is_none(){
+								tracing::error!("update description {}",file_id);
+							}
+						}
+					},
+					Err(e)=>tracing::error!("{:?}",e),
+				}
+			},
+			Err(e)=>{
+				//GETで失敗の理由を返せるように残す
+				let (status,message)=error_parts(&e);
+				let failed=[
+					("state","failed".to_owned()),
+					("status",status.as_u16().to_string()),
+					("error",message),
+				];
+				if let Err(e)=redis.hset_multiple::<&String,&str,String,()>(&key,&failed).await{
+					tracing::error!("{:?}",e);
+				}
+			},
+		}
+	});
+	json_response(StatusCode::ACCEPTED,body)
+}
+/**
+ * GET /api/v1/media/:id
+ * 処理中は206を返す
+ */
+pub async fn get(
+	mut ctx:Context,
+	headers:HeaderMap,
+	axum::extract::Path(file_id):axum::extract::Path<String>,
+)->axum::response::Response{
+	let me=match authenticate(&ctx,&headers).await{
+		Ok(me)=>me,
+		Err(e)=>return error_response(e),
+	};
+	let pending=match ctx.redis.hgetall::<String,std::collections::HashMap<String,String>>(pending_key(&file_id)).await{
+		Ok(pending)=>pending,
+		Err(e)=>return error_response(e.into()),
+	};
+	if pending.get("user_id").map(|user_id|user_id==&me.id).unwrap_or(false){
+		match pending.get("state").map(|s|s.as_str()){
+			Some("processing")=>{
+				let content_type=pending.get("type").map(|s|s.as_str()).unwrap_or_default();
+				let body=pending_attachment(&file_id,content_type,pending.get("description").map(|s|s.as_str()).filter(|s|!s.is_empty()));
+				return json_response(StatusCode::PARTIAL_CONTENT,body);
+			},
+			Some("failed")=>{
+				let status=pending.get("status").and_then(|s|s.parse::<u16>().ok()).and_then(|s|StatusCode::from_u16(s).ok()).unwrap_or(StatusCode::UNPROCESSABLE_ENTITY);
+				let message=pending.get("error").map(|s|s.as_str()).unwrap_or("Error processing thumbnail for uploaded media");
+				return json_response(status,serde_json::json!({"error":message}));
+			},
+			_=>{},
+		}
+	}
+	match ctx.drive_service.find_file(&me,&file_id).await{
+		Some((_file,Some(packed)))=>json_response(StatusCode::OK,attachment(&packed)),
+		Some((_file,None))=>error_response(ApiError::internal("pack")),
+		None=>json_response(StatusCode::NOT_FOUND,serde_json::json!({"error":"Record not found"})),
+	}
+}
+/**
+ * PUT /api/v1/media/:id
+ * descriptionをドライブのファイルのcommentに反映する
+ */
+pub async fn put(
+	mut ctx:Context,
+	headers:HeaderMap,
+	axum::extract::Path(file_id):axum::extract::Path<String>,
+	request: axum::extract::Request,
+)->axum::response::Response{
+	let me=match authenticate(&ctx,&headers).await{
+		Ok(me)=>me,
+		Err(e)=>return error_response(e),
+	};
+	let is_json=headers.get(axum::http::header::CONTENT_TYPE).and_then(|v|v.to_str().ok()).map(|v|v.starts_with("application/json")).unwrap_or(false);
+	let params=if is_json{
+		axum::Json::<UpdateParams>::from_request(request,&()).await.map(|v|v.0).map_err(|e|e.body_text())
+	}else{
+		axum::Form::<UpdateParams>::from_request(request,&()).await.map(|v|v.0).map_err(|e|e.body_text())
+	};
+	let params=match params{
+		Ok(params)=>params,
+		Err(e)=>return error_response(ApiError::InvalidParam(e)),
+	};
+	let description=params.description.filter(|s|!s.is_empty());
+	if let Err(e)=validate_description(description.as_deref()){
+		return error_response(e);
+	}
+	//処理中なら完了時に反映する
+	let script=redis::Script::new(SET_PENDING_DESCRIPTION_SCRIPT);
+	let pending=script.key(pending_key(&file_id)).arg(description.as_deref().unwrap_or_default()).arg(&me.id).invoke_async::<(i64,String)>(&mut ctx.redis).await;
+	match pending{
+		Ok((1,content_type))=>return json_response(StatusCode::OK,pending_attachment(&file_id,&content_type,description.as_deref())),
+		Ok((-1,_))=>return json_response(StatusCode::NOT_FOUND,serde_json::json!({"error":"Record not found"})),
+		Ok(_)=>{},
+		Err(e)=>return error_response(e.into()),
+	}
+	match ctx.drive_service.update_comment(&me,&file_id,description.as_deref()).await{
+		Some((_file,Some(packed)))=>json_response(StatusCode::OK,attachment(&packed)),
+		Some((_file,None))=>error_response(ApiError::internal("pack")),
+		None=>json_response(StatusCode::NOT_FOUND,serde_json::json!({"error":"Record not found"})),
+	}
+}
@@ -0,0 +1,51 @@
+use std::time::Instant;
+
+use axum::{extract::MatchedPath, http::StatusCode, middleware::Next, response::IntoResponse, Router};
+use futures::TryStreamExt;
+
+use crate::Context;
+
+pub fn route(ctx: &Context,app: Router)->Router{
+	//別のアドレスで公開する場合はmain側で待ち受ける
+	match ctx.config.load().metrics.as_ref(){
+		Some(config) if config.bind_addr.is_none()=>{},
+		_=>return app,
+	}
+	let ctx0=ctx.clone();
+	app.route("/metrics",axum::routing::get(move||metrics(ctx0.clone())))
+}
+pub async fn metrics(
+	ctx:Context,
+)->axum::response::Response{
+	match ctx.metrics.encode(){
+		Ok(buf)=>{
+			let mut header=axum::http::HeaderMap::new();
+			header.insert(axum::http::header::CONTENT_TYPE,"text/plain; version=0.0.4".parse().unwrap());
+			(StatusCode::OK,header,buf).into_response()
+		},
+		Err(e)=>{
+			tracing::error!("{:?}",e);
+			StatusCode::INTERNAL_SERVER_ERROR.into_response()
+		}
+	}
+}
+/**
+ * アップロード系の経路の受信量と処理時間を記録する
+ */
+pub async fn track_upload(
+	ctx:Context,
+	request: axum::extract::Request,
+	next:Next,
+)->axum::response::Response{
+	let start=Instant::now();
+	let endpoint=match request.extensions().get::<MatchedPath>(){
+		Some(path)=>path.as_str().to_owned(),
+		None=>request.uri().path().to_owned(),
+	};
+	let counter=ctx.metrics.upload_bytes.with_label_values(&[&endpoint]);
+	let (parts,body)=request.into_parts();
+	let body=axum::body::Body::from_stream(body.into_data_stream().inspect_ok(move|chunk|counter.inc_by(chunk.len() as u64)));
+	let res=next.run(axum::extract::Request::from_parts(parts,body)).await;
+	ctx.metrics.upload_duration.with_label_values(&[&endpoint,res.status().as_str()]).observe(start.elapsed().as_secs_f64());
+	res
+}
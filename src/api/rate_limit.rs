@@ -0,0 +1,222 @@
+use std::net::SocketAddr;
+
+use axum::{body::{Body, Bytes}, http::header::{CONTENT_LENGTH, CONTENT_TYPE}, middleware::Next, response::IntoResponse};
+use futures::StreamExt;
+
+use crate::{error::ApiError, service::rate_limit::RateLimitRule, Context};
+
+//本文からトークンを探すときに先読みする最大バイト数
+const PEEK_LIMIT:usize=16*1024;
+
+fn bearer_token(headers:&axum::http::HeaderMap)->Option<&str>{
+	headers.get(axum::http::header::AUTHORIZATION)?.to_str().ok()?.strip_prefix("Bearer ")
+}
+enum FieldSearch{
+	Found(String),
+	NotFound,
+	Incomplete,
+}
+/**
+ * multipartの先頭部分から指定したフィールドの値を探す
+ * ファイルのパートか終端に達したらそれ以降は探さない
+ */
+fn multipart_field(data:&[u8],boundary:&str,name:&str)->FieldSearch{
+	let start=format!("--{}",boundary);
+	let delimiter=format!("\r\n--{}",boundary);
+	let disposition=format!("; name=\"{}\"",name);
+	let mut rest=match find(data,start.as_bytes()){
+		Some(i)=>&data[i+start.len()..],
+		None=>return FieldSearch::Incomplete,
+	};
+	loop{
+		if rest.starts_with(b"--"){
+			return FieldSearch::NotFound;
+		}
+		let header_end=match find(rest,b"\r\n\r\n"){
+			Some(i)=>i,
+			None=>return FieldSearch::Incomplete,
+		};
+		let headers=String::from_utf8_lossy(&rest[..header_end]);
+		if headers.contains("; filename="){
+			return FieldSearch::NotFound;
+		}
+		let value=&rest[header_end+4..];
+		let value_end=match find(value,delimiter.as_bytes()){
+			Some(i)=>i,
+			None=>return FieldSearch::Incomplete,
+		};
+		if headers.contains(&disposition){
+			return match std::str::from_utf8(&value[..value_end]){
+				Ok(v)=>FieldSearch::Found(v.to_owned()),
+				Err(_)=>FieldSearch::NotFound,
+			};
+		}
+		rest=&value[value_end+delimiter.len()..];
+	}
+}
+fn find(data:&[u8],needle:&[u8])->Option<usize>{
+	data.windows(needle.len()).position(|w|w==needle)
+}
+/**
+ * JSONかmultipartの本文の先頭からiを探す
+ * 読んだ分は本文の前に戻して後続のハンドラに渡す
+ */
+async fn body_token(request:axum::extract::Request)->(axum::extract::Request,Option<String>){
+	let content_type=request.headers().get(CONTENT_TYPE).and_then(|v|v.to_str().ok()).unwrap_or_default();
+	let boundary=content_type.strip_prefix("multipart/form-data").and_then(|v|{
+		v.split(';').find_map(|p|p.trim().strip_prefix("boundary=")).map(|b|b.trim_matches('"').to_owned())
+	});
+	let is_json=content_type.starts_with("application/json");
+	let content_length=request.headers().get(CONTENT_LENGTH).and_then(|v|v.to_str().ok()).and_then(|v|v.parse::<usize>().ok());
+	//JSONは全体を読まないと解析できないので小さいものに限る
+	if is_json&&!content_length.is_some_and(|l|l<=PEEK_LIMIT){
+		return (request,None);
+	}
+	if !is_json&&boundary.is_none(){
+		return (request,None);
+	}
+	let (parts,body)=request.into_parts();
+	let mut stream=body.into_data_stream();
+	let mut chunks:Vec<Result<Bytes,axum::Error>>=vec![];
+	let mut data=Vec::new();
+	let mut token=None;
+	while data.len()<=PEEK_LIMIT{
+		match stream.next().await{
+			Some(Ok(chunk))=>{
+				data.extend_from_slice(&chunk);
+				chunks.push(Ok(chunk));
+			},
+			Some(Err(e))=>{
+				chunks.push(Err(e));
+				break;
+			},
+			None=>{
+				if is_json{
+					token=serde_json::from_slice::<serde_json::Value>(&data).ok().and_then(|v|v["i"].as_str().map(|v|v.to_owned()));
+				}
+				break;
+			},
+		}
+		if let Some(boundary)=boundary.as_deref(){
+			match multipart_field(&data,boundary,"i"){
+				FieldSearch::Found(v)=>{
+					token=Some(v);
+					break;
+				},
+				FieldSearch::NotFound=>break,
+				FieldSearch::Incomplete=>{},
+			}
+		}
+	}
+	let body=Body::from_stream(futures::stream::iter(chunks).chain(stream));
+	(axum::extract::Request::from_parts(parts,body),token)
+}
+/**
+ * rate_limitsに一致したリクエストを制限する
+ * ユーザーが分かる場合はユーザー毎、それ以外は接続元IP毎に数える
+ * ユーザーはAuthorizationヘッダか、無ければ本文の先頭にあるiから判別する
+ * 最近認証できていないトークンは、DBに問い合わせる前に接続元IPの分も消費する
+ */
+pub async fn limit(
+	ctx:Context,
+	axum::extract::ConnectInfo(addr):axum::extract::ConnectInfo<SocketAddr>,
+	request: axum::extract::Request,
+	next:Next,
+)->axum::response::Response{
+	let config=ctx.config.load();
+	let rules=match config.rate_limits.as_ref(){
+		Some(rules)=>rules,
+		None=>return next.run(request).await,
+	};
+	let rule=rules.iter().find(|r|{
+		super::route_table::match_method(r.methods.as_ref(),request.method())&&super::route_table::match_path(&r.path,request.uri().path())
+	});
+	let rule=match rule{
+		Some(rule)=>rule,
+		None=>return next.run(request).await,
+	};
+	let (request,token)=match bearer_token(request.headers()){
+		Some(token)=>{
+			let token=token.to_owned();
+			(request,Some(token))
+		},
+		None=>body_token(request).await,
+	};
+	let ip=crate::client_ip::client_ip(&config,&addr,request.headers());
+	let ip_key=format!("{}:ip:{}",rule.path,ip);
+	let token=match token{
+		Some(token)=>token,
+		None=>return consume(&ctx,&ip_key,rule,1.0,request,next).await,
+	};
+	let (user_id,factor)=match ctx.rate_limit_service.cached_user(&token).await{
+		Some(user)=>user,
+		None=>{
+			//不正なトークンを大量に送ってDBへの問い合わせを増やせないようにする
+			if let Err(res)=check(&ctx,&ip_key,rule,1.0).await{
+				return res;
+			}
+			match ctx.user_service.authenticate(&token).await{
+				Some(user)=>{
+					let policies=ctx.role_service.get_user_policies(Some(user.id.as_str())).await;
+					let factor=policies.rate_limit_factor.unwrap_or(1.0);
+					ctx.rate_limit_service.cache_user(&token,&user.id,factor).await;
+					(user.id,factor)
+				},
+				//接続元IPの分は消費済み
+				None=>return next.run(request).await,
+			}
+		}
+	};
+	super::access_log::record_user(&user_id);
+	consume(&ctx,&format!("{}:user:{}",rule.path,user_id),rule,factor,request,next).await
+}
+async fn check(ctx:&Context,key:&str,rule:&RateLimitRule,factor:f64)->Result<(),axum::response::Response>{
+	//Misskeyと同じくfactorが0以下なら制限しない
+	if factor<=0.0{
+		return Ok(());
+	}
+	ctx.rate_limit_service.consume(key,rule,factor).await.map_err(|retry_ms|ApiError::RateLimitExceeded{
+		retry_after_ms:retry_ms,
+	}.into_response())
+}
+async fn consume(ctx:&Context,key:&str,rule:&RateLimitRule,factor:f64,request:axum::extract::Request,next:Next)->axum::response::Response{
+	match check(ctx,key,rule,factor).await{
+		Ok(_)=>next.run(request).await,
+		Err(res)=>res,
+	}
+}
+#[cfg(test)]
+mod tests{
+	use super::*;
+
+	const BOUNDARY:&str="xyz";
+	fn part(name:&str,filename:Option<&str>,value:&str)->String{
+		let filename=filename.map(|f|format!("; filename=\"{}\"",f)).unwrap_or_default();
+		format!("--{}\r\nContent-Disposition: form-data; name=\"{}\"{}\r\n\r\n{}\r\n",BOUNDARY,name,filename,value)
+	}
+	#[test]
+	fn token_before_file(){
+		let body=format!("{}{}{}--{}--\r\n",part("force",None,"true"),part("i",None,"token"),part("file",Some("a.png"),"data"),BOUNDARY);
+		assert!(matches!(multipart_field(body.as_bytes(),BOUNDARY,"i"),FieldSearch::Found(v) if v=="token"));
+	}
+	#[test]
+	fn token_after_file(){
+		let body=format!("{}{}--{}--\r\n",part("file",Some("a.png"),"data"),part("i",None,"token"),BOUNDARY);
+		assert!(matches!(multipart_field(body.as_bytes(),BOUNDARY,"i"),FieldSearch::NotFound));
+	}
+	#[test]
+	fn file_named_like_the_field(){
+		let body=format!("{}--{}--\r\n",part("file",Some("i"),"data"),BOUNDARY);
+		assert!(matches!(multipart_field(body.as_bytes(),BOUNDARY,"i"),FieldSearch::NotFound));
+	}
+	#[test]
+	fn incomplete(){
+		let body=part("i",None,"token");
+		//値の後の区切りがまだ届いていない
+		let body=&body.as_bytes()[..body.len()-2];
+		assert!(matches!(multipart_field(body,BOUNDARY,"i"),FieldSearch::Incomplete));
+		assert!(matches!(multipart_field(b"--xy",BOUNDARY,"i"),FieldSearch::Incomplete));
+		let body=format!("{}--{}--\r\n",part("name",None,"a.png"),BOUNDARY);
+		assert!(matches!(multipart_field(body.as_bytes(),BOUNDARY,"i"),FieldSearch::NotFound));
+	}
+}
@@ -0,0 +1,144 @@
+use std::net::SocketAddr;
+
+use axum::{http::StatusCode, middleware::Next, response::IntoResponse};
+use serde::{Deserialize, Serialize};
+
+use crate::Context;
+
+/**
+ * 設定ファイルの経路表の1項目
+ * pathは"*"を任意の文字列として扱う
+ */
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub struct RouteRule{
+	path:String,
+	//省略時は全てのメソッド
+	methods:Option<Vec<String>>,
+	action:RouteAction,
+	//action=Backendの転送先(upstream.backendsのname)、省略時は通常の振り分け
+	backend:Option<String>,
+	//action=Rejectで返すステータス、省略時は404
+	status:Option<u16>,
+}
+#[derive(Clone, Copy,Debug,Serialize,Deserialize)]
+pub enum RouteAction{
+	Local,
+	Backend,
+	Reject,
+}
+pub(crate) fn match_path(pattern:&str,path:&str)->bool{
+	let mut parts=pattern.split('*');
+	let first=parts.next().unwrap_or_default();
+	let mut rest=match path.strip_prefix(first){
+		Some(rest)=>rest,
+		None=>return false,
+	};
+	let parts:Vec<&str>=parts.collect();
+	if parts.is_empty(){
+		return rest.is_empty();
+	}
+	for (i,part) in parts.iter().enumerate(){
+		if i==parts.len()-1{
+			return rest.ends_with(part);
+		}
+		match rest.find(part){
+			Some(idx)=>rest=&rest[idx+part.len()..],
+			None=>return false,
+		}
+	}
+	true
+}
+pub(crate) fn match_method(methods:Option<&Vec<String>>,method:&axum::http::Method)->bool{
+	match methods{
+		Some(methods)=>methods.iter().any(|m|m.eq_ignore_ascii_case(method.as_str())),
+		None=>true,
+	}
+}
+impl RouteRule{
+	fn matches(&self,method:&axum::http::Method,path:&str)->bool{
+		match_method(self.methods.as_ref(),method)&&match_path(&self.path,path)
+	}
+	pub fn validate(&self,backend_names:&[&str],errors:&mut Vec<String>){
+		if !self.path.starts_with('/'){
+			errors.push(format!("routes[{}].path: must start with \"/\"",self.path));
+		}
+		for method in self.methods.iter().flatten(){
+			if axum::http::Method::from_bytes(method.as_bytes()).is_err(){
+				errors.push(format!("routes[{}].methods: invalid method {:?}",self.path,method));
+			}
+		}
+		if let Some(backend)=self.backend.as_ref(){
+			if !backend_names.contains(&backend.as_str()){
+				errors.push(format!("routes[{}].backend: unknown backend {:?}",self.path,backend));
+			}
+		}
+		if let Some(status)=self.status{
+			if StatusCode::from_u16(status).is_err(){
+				errors.push(format!("routes[{}].status: invalid status {}",self.path,status));
+			}
+		}
+	}
+}
+/**
+ * 経路表に従ってローカルで処理するか、バックエンドに転送するか、拒否するかを決める
+ * どの規則にも一致しなければ組み込みの経路に任せる
+ */
+pub async fn dispatch(
+	ctx:Context,
+	connect_info:axum::extract::ConnectInfo<SocketAddr>,
+	request: axum::extract::Request,
+	next:Next,
+)->axum::response::Response{
+	let config=ctx.config.load();
+	let rule=match config.routes.as_ref(){
+		Some(routes)=>routes.iter().find(|r|r.matches(request.method(),request.uri().path())),
+		None=>None,
+	};
+	let rule=match rule{
+		Some(rule)=>rule,
+		None=>return next.run(request).await,
+	};
+	match rule.action{
+		RouteAction::Local=>next.run(request).await,
+		RouteAction::Backend=>{
+			let backend=rule.backend.clone();
+			crate::api::default_route::proxy_to(ctx.clone(),connect_info,request,backend.as_deref()).await
+		},
+		RouteAction::Reject=>{
+			StatusCode::from_u16(rule.status.unwrap_or(404)).unwrap_or(StatusCode::NOT_FOUND).into_response()
+		},
+	}
+}
+#[cfg(test)]
+mod tests{
+	use super::match_path;
+
+	#[test]
+	fn exact(){
+		assert!(match_path("/api/meta","/api/meta"));
+		assert!(!match_path("/api/meta","/api/meta/"));
+		assert!(!match_path("/api/meta","/api"));
+	}
+	#[test]
+	fn trailing_glob(){
+		assert!(match_path("/api/*","/api/"));
+		assert!(match_path("/api/*","/api/drive/files/create"));
+		assert!(!match_path("/api/*","/api"));
+		assert!(!match_path("/api/*","/apix"));
+		assert!(match_path("*","/"));
+		assert!(match_path("*",""));
+	}
+	#[test]
+	fn inner_glob(){
+		assert!(match_path("/files/*/thumbnail","/files/abc/thumbnail"));
+		assert!(match_path("/files/*/thumbnail","/files//thumbnail"));
+		assert!(!match_path("/files/*/thumbnail","/files/thumbnail"));
+		assert!(match_path("/a*b*c","/abc"));
+		assert!(match_path("/a*b*c","/axxbyyc"));
+		assert!(!match_path("/a*b*c","/axxcyyb"));
+		//末尾の固定部分は前の固定部分と重ならない
+		assert!(!match_path("/ab*b","/ab"));
+		assert!(match_path("/ab*b","/abb"));
+		assert!(match_path("/a**b","/ab"));
+	}
+}
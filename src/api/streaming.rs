@@ -0,0 +1,509 @@
+use std::{collections::{HashMap, VecDeque}, net::SocketAddr, sync::{Arc, Mutex}, time::Duration};
+
+use axum::extract::ws::{CloseFrame, Message, WebSocketUpgrade};
+use futures::{stream::{BoxStream, SplitSink, SplitStream}, SinkExt, Stream, StreamExt};
+use reqwest::Client;
+use serde::Deserialize;
+use tokio::sync::broadcast;
+use tokio_util::sync::CancellationToken;
+use tracing::Instrument;
+
+use crate::{api::access_log::X_REQUEST_ID, service::{event::{StreamChannels, StreamEvent}, upstream::UpstreamGuard}, Context};
+
+//IANA登録済みの1014 Bad Gateway
+const CLOSE_BAD_GATEWAY:u16=1014;
+const CLOSE_GOING_AWAY:u16=1001;
+//プロキシが直接配信するイベント(クライアント側のチャンネル名,イベント名)
+const INJECT_EVENTS:[(&str,&str);4]=[
+	("main","driveFileCreated"),
+	("drive","fileCreated"),
+	("drive","uploadProgress"),
+	("drive","fileProcessing"),
+];
+//重複排除のために覚えておく配信済みイベントの数
+const SEEN_CAPACITY:usize=256;
+
+#[derive(Debug, Deserialize)]
+pub struct StreamingParams{
+	#[serde(rename = "i")]
+	token:Option<String>,
+}
+pub async fn streaming(
+	ctx:Context,
+	axum::extract::ConnectInfo(addr):axum::extract::ConnectInfo<SocketAddr>,
+	headers:axum::http::HeaderMap,
+	ws: WebSocketUpgrade,
+	axum::extract::Query(q):axum::extract::Query<StreamingParams>,
+)->axum::response::Response{
+	//同じユーザー(未ログインなら同じIP)の接続は同じバックエンドに振り分ける
+	let sticky_key=match q.token.as_ref(){
+		Some(token)=>token.clone(),
+		None=>crate::client_ip::client_ip(&ctx.config.load(),&addr,&headers).to_string(),
+	};
+	//バックエンドにクライアントのアドレスとリクエストIDを伝える
+	let mut backend_headers=crate::client_ip::forwarded_headers(&ctx.config.load(),&addr,&headers);
+	if let Some(request_id)=headers.get(X_REQUEST_ID){
+		backend_headers.insert(X_REQUEST_ID,request_id.clone());
+	}
+	let span=tracing::Span::current();
+	let tasks=ctx.tasks.clone();
+	ws.on_upgrade(move|socket| tasks.track_future(handle_socket(socket, ctx,q,sticky_key,backend_headers).instrument(span)))
+}
+/**
+ * クライアントが接続中のチャンネル一覧(接続ID→チャンネル名)
+ */
+type Connections=Arc<Mutex<HashMap<String,String>>>;
+async fn handle_socket(
+	mut socket: axum::extract::ws::WebSocket,
+	ctx:Context,
+	q:StreamingParams,
+	sticky_key:String,
+	backend_headers:axum::http::HeaderMap,
+) {
+	let user=async{
+		match q.token.as_deref(){
+			Some(token)=>ctx.user_service.authenticate(token).await,
+			None=>None,
+		}
+	};
+	let (backend,user)=futures::join!(connect_backend(&ctx,&sticky_key,q.token.as_deref(),&backend_headers),user);
+	//接続数を数えるためにguardは切断まで保持する
+	let (backend,_upstream)=match backend{
+		Ok(backend)=>backend,
+		Err(e)=>{
+			tracing::error!("backend ws error {:?}",e);
+			let frame=CloseFrame{
+				code:CLOSE_BAD_GATEWAY,
+				reason:"backend unavailable".into(),
+			};
+			let _=socket.send(Message::Close(Some(frame))).await;
+			return;
+		}
+	};
+	if let Some(user)=user.as_ref(){
+		crate::api::access_log::record_user(&user.id);
+	}
+	let connections=Connections::default();
+	let injector=user.map(|user|Injector{
+		main_channel:StreamChannels::Main(&user.id).channel_id(),
+		drive_channel:StreamChannels::Drive(&user.id).channel_id(),
+		connections:connections.clone(),
+		seen:VecDeque::new(),
+	});
+	let idle=ctx.config.load().websocket_idle_timeout.unwrap_or(60);
+	let idle=(idle>0).then(||Duration::from_secs(idle));
+	//切断やタスクの中断で終了しても接続数を戻すためにguardで数える
+	let _connection=ctx.metrics.websocket_connection();
+	let (sender, receiver) = socket.split();
+	let (backend_sender, backend_receiver) = backend.split();
+	let read=ws_read_side(receiver,backend_sender,connections,idle);
+	let write=ws_write_side(sender,backend_receiver,injector,ctx.event_service.subscribe(),ctx.shutdown.clone(),idle);
+	futures::pin_mut!(read,write);
+	//どちらかの方向が終了したら接続全体を終了する
+	futures::future::select(read,write).await;
+	tracing::debug!("exit handle_socket");
+}
+//1005,1006,1015は実際のフレームで送信してはいけない
+fn is_sendable_close_code(code:u16)->bool{
+	!matches!(code,1005|1006|1015)
+}
+/**
+ * クライアントからのconnect/disconnectを記録する
+ */
+fn track_connection(connections:&Connections,text:&str){
+	if !text.contains("connect"){
+		return;
+	}
+	let v=match serde_json::from_str::<serde_json::Value>(text){
+		Ok(v)=>v,
+		Err(_)=>return,
+	};
+	let body=&v["body"];
+	let id=match body["id"].as_str(){
+		Some(id)=>id.to_owned(),
+		None=>return,
+	};
+	let mut connections=match connections.lock(){
+		Ok(c)=>c,
+		Err(_)=>return,
+	};
+	match (v["type"].as_str(),body["channel"].as_str()){
+		(Some("connect"),Some(channel))=>{
+			connections.insert(id,channel.to_owned());
+		},
+		(Some("disconnect"),_)=>{
+			connections.remove(&id);
+		},
+		_=>{}
+	}
+}
+/**
+ * 無通信の判定とPingの送信に使う、idleの半分ごとの通知
+ */
+fn keepalive(idle:Option<Duration>)->BoxStream<'static,()>{
+	let idle=match idle{
+		Some(idle)=>idle,
+		None=>return futures::stream::pending().boxed(),
+	};
+	let period=idle/2;
+	let interval=tokio::time::interval_at(tokio::time::Instant::now()+period,period);
+	futures::stream::unfold(interval,|mut interval|async move{
+		interval.tick().await;
+		Some(((),interval))
+	}).boxed()
+}
+enum ReadEvent{
+	Client(Option<Result<Message,axum::Error>>),
+	Keepalive,
+}
+async fn ws_read_side(
+	receiver: SplitStream<axum::extract::ws::WebSocket>,
+	mut backend_sender: SplitSink<reqwest_websocket::WebSocket, reqwest_websocket::Message>,
+	connections:Connections,
+	idle:Option<Duration>,
+) {
+	//クライアントの切断を検知するためにNoneを末尾に付ける
+	let client=receiver.map(|m|ReadEvent::Client(Some(m))).chain(futures::stream::iter([ReadEvent::Client(None)]));
+	let mut events=futures::stream::select(client,keepalive(idle).map(|_|ReadEvent::Keepalive));
+	let mut last_read=tokio::time::Instant::now();
+	while let Some(event)=events.next().await{
+		let message=match event{
+			ReadEvent::Client(Some(message))=>message,
+			ReadEvent::Client(None)=>return,
+			ReadEvent::Keepalive=>{
+				if idle.is_some_and(|idle|last_read.elapsed()>idle){
+					tracing::debug!("client idle timeout");
+					let _=backend_sender.send(reqwest_websocket::Message::Close{
+						code:reqwest_websocket::CloseCode::Away,
+						reason:String::new(),
+					}).await;
+					return;
+				}
+				//バックエンドとの間の死活確認、Pongは書き込み側で受け取る
+				if let Err(e)=backend_sender.send(reqwest_websocket::Message::Ping(Vec::new())).await{
+					tracing::warn!("WS send to backend error {:?}",e);
+					return;
+				}
+				continue;
+			},
+		};
+		last_read=tokio::time::Instant::now();
+		let message=match message{
+			Ok(message)=>message,
+			Err(e)=>{
+				tracing::warn!("Read from client MessageError: {:?}", e);
+				let _=backend_sender.send(reqwest_websocket::Message::Close{
+					code:reqwest_websocket::CloseCode::Away,
+					reason:String::new(),
+				}).await;
+				return;
+			}
+		};
+		let (message,close)=match message{
+			Message::Text(text)=>{
+				track_connection(&connections,&text);
+				(reqwest_websocket::Message::Text(text),false)
+			},
+			Message::Binary(data)=>(reqwest_websocket::Message::Binary(data),false),
+			//Pingへの応答はaxumが自動で返すので中継しない
+			Message::Ping(_)|Message::Pong(_)=>continue,
+			Message::Close(frame)=>{
+				let (code,reason)=match frame{
+					Some(frame) if is_sendable_close_code(frame.code)=>(frame.code,frame.reason.into_owned()),
+					_=>(1000,String::new()),
+				};
+				(reqwest_websocket::Message::Close{
+					code:code.into(),
+					reason,
+				},true)
+			},
+		};
+		if let Err(e)=backend_sender.send(message).await{
+			tracing::warn!("WS send to backend error {:?}",e);
+			return;
+		}
+		if close{
+			tracing::debug!("close from client");
+			return;
+		}
+	}
+}
+/**
+ * Redisから受け取ったドライブのイベントをバックエンドを待たずにクライアントへ配信する
+ * バックエンドも同じイベントを中継してくるので、接続ID,イベント名,ファイルIDで重複を除く
+ * ファイルIDの無い進捗のイベントは本文全体で区別する
+ */
+struct Injector{
+	main_channel:String,
+	drive_channel:String,
+	connections:Connections,
+	seen:VecDeque<String>,
+}
+impl Injector{
+	fn is_injected(channel:&str,event_type:&str)->bool{
+		INJECT_EVENTS.contains(&(channel,event_type))
+	}
+	fn event_key(body:&serde_json::Value)->String{
+		match body["id"].as_str(){
+			Some(file_id)=>file_id.to_owned(),
+			None=>body.to_string(),
+		}
+	}
+	/**
+	 * 未配信ならtrueを返して配信済みとして記録する
+	 */
+	fn mark(&mut self,connection_id:&str,event_type:&str,file_id:&str)->bool{
+		let key=format!("{}:{}:{}",connection_id,event_type,file_id);
+		if self.seen.contains(&key){
+			return false;
+		}
+		if self.seen.len()>=SEEN_CAPACITY{
+			self.seen.pop_front();
+		}
+		self.seen.push_back(key);
+		true
+	}
+	fn local_event(&mut self,event:&StreamEvent)->Vec<String>{
+		let channel=if event.channel==self.main_channel{
+			"main"
+		}else if event.channel==self.drive_channel{
+			"drive"
+		}else{
+			return vec![];
+		};
+		let event_type=match event.message["type"].as_str(){
+			Some(t) if Self::is_injected(channel,t)=>t,
+			_=>return vec![],
+		};
+		let body=&event.message["body"];
+		let event_key=Self::event_key(body);
+		let targets:Vec<String>=match self.connections.lock(){
+			Ok(connections)=>connections.iter().filter(|(_,c)|c.as_str()==channel).map(|(id,_)|id.clone()).collect(),
+			Err(_)=>return vec![],
+		};
+		let mut messages=vec![];
+		for connection_id in targets{
+			if !self.mark(&connection_id,event_type,&event_key){
+				continue;
+			}
+			let message=serde_json::json!({
+				"type":"channel",
+				"body":{
+					"id":connection_id,
+					"type":event_type,
+					"body":body,
+				},
+			});
+			if let Ok(message)=serde_json::to_string(&message){
+				messages.push(message);
+			}
+		}
+		messages
+	}
+	/**
+	 * バックエンドからのメッセージを転送すべきか判定する
+	 */
+	fn backend_event(&mut self,text:&str)->bool{
+		if !INJECT_EVENTS.iter().any(|(_,event_type)|text.contains(event_type)){
+			return true;
+		}
+		let v=match serde_json::from_str::<serde_json::Value>(text){
+			Ok(v)=>v,
+			Err(_)=>return true,
+		};
+		if v["type"].as_str()!=Some("channel"){
+			return true;
+		}
+		let body=&v["body"];
+		let (connection_id,event_type)=match (body["id"].as_str(),body["type"].as_str()){
+			(Some(connection_id),Some(event_type))=>(connection_id,event_type),
+			_=>return true,
+		};
+		let event_key=Self::event_key(&body["body"]);
+		let channel=match self.connections.lock(){
+			Ok(connections)=>connections.get(connection_id).cloned(),
+			Err(_)=>None,
+		};
+		match channel{
+			Some(channel) if Self::is_injected(&channel,event_type)=>self.mark(connection_id,event_type,&event_key),
+			_=>true,
+		}
+	}
+}
+enum WriteEvent{
+	Backend(Option<Result<reqwest_websocket::Message,reqwest_websocket::Error>>),
+	Local(Arc<StreamEvent>),
+	Shutdown,
+	Keepalive,
+}
+fn local_events(rx:broadcast::Receiver<Arc<StreamEvent>>)->impl Stream<Item=Arc<StreamEvent>>{
+	futures::stream::unfold(rx,|mut rx|async move{
+		loop{
+			match rx.recv().await{
+				Ok(event)=>return Some((event,rx)),
+				//取りこぼしたイベントはバックエンド経由で届く
+				Err(broadcast::error::RecvError::Lagged(_))=>continue,
+				Err(broadcast::error::RecvError::Closed)=>return None,
+			}
+		}
+	})
+}
+async fn ws_write_side(
+	mut sender: SplitSink<axum::extract::ws::WebSocket,axum::extract::ws::Message>,
+	backend_receiver: SplitStream<reqwest_websocket::WebSocket>,
+	mut injector:Option<Injector>,
+	local:broadcast::Receiver<Arc<StreamEvent>>,
+	shutdown:CancellationToken,
+	idle:Option<Duration>,
+) {
+	//バックエンドの終了を検知するためにNoneを末尾に付ける
+	let backend=backend_receiver.map(|m|WriteEvent::Backend(Some(m))).chain(futures::stream::iter([WriteEvent::Backend(None)]));
+	let local:BoxStream<'static,WriteEvent>=if injector.is_some(){
+		local_events(local).map(WriteEvent::Local).boxed()
+	}else{
+		futures::stream::pending().boxed()
+	};
+	let shutdown=futures::stream::once(shutdown.cancelled_owned()).map(|_|WriteEvent::Shutdown).boxed();
+	let keepalive=keepalive(idle).map(|_|WriteEvent::Keepalive);
+	let mut events=futures::stream::select(futures::stream::select(backend,local),futures::stream::select(shutdown,keepalive));
+	let mut last_read=tokio::time::Instant::now();
+	while let Some(event)=events.next().await{
+		if let WriteEvent::Backend(_)=event{
+			last_read=tokio::time::Instant::now();
+		}
+		let message=match event{
+			WriteEvent::Local(event)=>{
+				if let Some(injector)=injector.as_mut(){
+					for message in injector.local_event(&event){
+						if let Err(e)=sender.send(Message::Text(message)).await{
+							tracing::warn!("WS send to client error {:?}",e);
+							return;
+						}
+					}
+				}
+				continue;
+			},
+			WriteEvent::Backend(Some(Ok(message)))=>message,
+			WriteEvent::Backend(Some(Err(e)))=>{
+				tracing::warn!("Read from backend MessageError: {:?}", e);
+				break;
+			},
+			WriteEvent::Backend(None)=>break,
+			WriteEvent::Shutdown=>{
+				let frame=CloseFrame{
+					code:CLOSE_GOING_AWAY,
+					reason:"server shutting down".into(),
+				};
+				let _=sender.send(Message::Close(Some(frame))).await;
+				return;
+			},
+			WriteEvent::Keepalive=>{
+				if idle.is_some_and(|idle|last_read.elapsed()>idle){
+					tracing::warn!("backend idle timeout");
+					let frame=CloseFrame{
+						code:CLOSE_BAD_GATEWAY,
+						reason:"backend idle timeout".into(),
+					};
+					let _=sender.send(Message::Close(Some(frame))).await;
+					return;
+				}
+				//クライアントとの間の死活確認、Pongは読み込み側で受け取る
+				if let Err(e)=sender.send(Message::Ping(Vec::new())).await{
+					tracing::warn!("WS send to client error {:?}",e);
+					return;
+				}
+				continue;
+			},
+		};
+		let (message,close)=match message{
+			reqwest_websocket::Message::Text(text)=>{
+				if let Some(injector)=injector.as_mut(){
+					if !injector.backend_event(&text){
+						continue;
+					}
+				}
+				(Message::Text(text),false)
+			},
+			reqwest_websocket::Message::Binary(data)=>(Message::Binary(data),false),
+			//Pingへの応答はreqwest_websocketが自動で返すので中継しない
+			reqwest_websocket::Message::Ping(_)|reqwest_websocket::Message::Pong(_)=>continue,
+			reqwest_websocket::Message::Close{code,reason}=>{
+				let code:u16=code.into();
+				let frame=if is_sendable_close_code(code){
+					Some(CloseFrame{
+						code,
+						reason:reason.into(),
+					})
+				}else{
+					None
+				};
+				(Message::Close(frame),true)
+			},
+		};
+		if let Err(e)=sender.send(message).await{
+			tracing::warn!("WS send to client error {:?}",e);
+			return;
+		}
+		if close{
+			tracing::debug!("close from backend");
+			return;
+		}
+	}
+	//バックエンドとの接続が異常終了した
+	let frame=CloseFrame{
+		code:CLOSE_BAD_GATEWAY,
+		reason:"backend connection lost".into(),
+	};
+	let _=sender.send(Message::Close(Some(frame))).await;
+}
+
+/**
+ * 接続できるまでバックエンドを順に試す
+ */
+async fn connect_backend(ctx:&Context,sticky_key:&str,token:Option<&str>,headers:&axum::http::HeaderMap)->Result<(reqwest_websocket::WebSocket,UpstreamGuard),String>{
+	let mut last_error="no backend".to_owned();
+	let upstream_service=ctx.upstream_service.load();
+	for _ in 0..upstream_service.len(){
+		let upstream=match upstream_service.pick_sticky(sticky_key){
+			Some(upstream)=>upstream,
+			None=>break,
+		};
+		match ws_backend(ctx.client.clone(),upstream.url(),token,headers).await{
+			Ok(backend)=>return Ok((backend,upstream)),
+			//バックエンドが応答した上での拒否は他のバックエンドでも同じ結果になる
+			Err(BackendError::Rejected(e))=>return Err(e),
+			Err(BackendError::Unreachable(e))=>{
+				tracing::warn!("backend {} ws error {:?}",upstream.name(),e);
+				upstream.mark_failed();
+				last_error=e;
+			}
+		}
+	}
+	Err(last_error)
+}
+enum BackendError{
+	Unreachable(String),
+	Rejected(String),
+}
+async fn ws_backend(client:Client,backend_url:&str,token:Option<&str>,headers:&axum::http::HeaderMap)->Result<reqwest_websocket::WebSocket,BackendError>{
+	use reqwest_websocket::RequestBuilderExt;
+	let mut url=reqwest::Url::parse(backend_url).map_err(|e|BackendError::Rejected(e.to_string()))?;
+	let scheme=if url.scheme()=="http"{
+		"ws"
+	}else{
+		"wss"
+	};
+	url.set_scheme(scheme).map_err(|_|BackendError::Rejected(format!("URL scheme error {}",backend_url)))?;
+	url.set_path("streaming");
+	if let Some(token)=token{
+		let query=format!("i={}",token);
+		url.set_query(Some(&query));
+	}
+	let response = client.get(url)
+		.headers(headers.clone())
+		.upgrade()
+		.send()
+		.await.map_err(|e|BackendError::Unreachable(e.to_string()))?;
+	let websocket = response.into_websocket().await.map_err(|e|BackendError::Rejected(e.to_string()))?;
+	Ok(websocket)
+}
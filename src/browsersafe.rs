@@ -37,3 +37,45 @@ pub const FILE_TYPE_BROWSERSAFE: [&str; 29] = [
 	"audio/x-flac",
 	"audio/vnd.wave",
 ];
+/**
+ * ブラウザで安全に表示できる種類に揃える
+ */
+pub fn normalize(content_type:&str)->&str{
+	let content_type=if content_type == "image/apng"{
+		"image/png"
+	}else{
+		content_type
+	};
+	if FILE_TYPE_BROWSERSAFE.contains(&content_type){
+		content_type
+	}else{
+		"application/octet-stream"
+	}
+}
+/**
+ * ファイルの先頭から種類と拡張子を判定する
+ */
+pub fn detect(buf:&[u8])->(&'static str,Option<String>){
+	let mut ext=None;
+	let mut content_type="";
+	if let Some(kind)=infer::get(buf){
+		content_type=kind.mime_type();
+		ext=Some(format!(".{}",kind.extension()));
+	}
+	if ext.as_ref().map(|s|s.as_str()) == Some("") {
+		ext=match content_type{
+			"image/jpeg"=>Some(".jpg"),
+			"image/png"=>Some(".png"),
+			"image/webp"=>Some(".webp"),
+			"image/avif"=>Some(".avif"),
+			"image/apng"=>Some(".apng"),
+			"image/vnd.mozilla.apng"=>Some(".apng"),
+			_=>None,
+		}.map(|s|s.to_owned());
+	}
+	let normalized=normalize(content_type);
+	if normalized!=content_type&&normalized=="application/octet-stream"{
+		ext = None;
+	}
+	(normalized,ext)
+}
@@ -0,0 +1,201 @@
+use std::net::{IpAddr, SocketAddr};
+
+use axum::http::{HeaderMap, HeaderValue};
+
+use crate::ConfigFile;
+
+const X_FORWARDED_FOR:&str="x-forwarded-for";
+const X_FORWARDED_PROTO:&str="x-forwarded-proto";
+const X_REAL_IP:&str="x-real-ip";
+
+/**
+ * trusted_proxiesの1項目とアドレスを比較する
+ * "192.168.0.1"のような単一アドレスと"10.0.0.0/8"のようなCIDR表記を受け付ける
+ */
+fn match_proxy(entry:&str,addr:&IpAddr)->bool{
+	let (net,prefix)=match entry.split_once('/'){
+		Some((net,prefix))=>(net,prefix.parse::<u32>().ok()),
+		None=>(entry,None),
+	};
+	let net=match net.trim().parse::<IpAddr>(){
+		Ok(net)=>net,
+		Err(_)=>return false,
+	};
+	match (net,addr.to_canonical()){
+		(IpAddr::V4(net),IpAddr::V4(addr))=>{
+			let prefix=prefix.unwrap_or(32).min(32);
+			let mask=u32::MAX.checked_shl(32-prefix).unwrap_or(0);
+			u32::from(net)&mask==u32::from(addr)&mask
+		},
+		(IpAddr::V6(net),IpAddr::V6(addr))=>{
+			let prefix=prefix.unwrap_or(128).min(128);
+			let mask=u128::MAX.checked_shl(128-prefix).unwrap_or(0);
+			u128::from(net)&mask==u128::from(addr)&mask
+		},
+		_=>false,
+	}
+}
+pub fn is_trusted_proxy(config:&ConfigFile,addr:&IpAddr)->bool{
+	match config.trusted_proxies.as_ref(){
+		Some(list)=>list.iter().any(|entry|match_proxy(entry,addr)),
+		None=>false,
+	}
+}
+fn forwarded_for(headers:&HeaderMap)->Vec<IpAddr>{
+	headers.get_all(X_FORWARDED_FOR).iter().filter_map(|v|v.to_str().ok()).flat_map(|v|v.split(',')).filter_map(|v|v.trim().parse().ok()).collect()
+}
+/**
+ * 接続元がtrusted_proxiesに含まれる場合のみX-Forwarded-Forを信用する
+ * 右から辿って最初に見つかった信用できないアドレスをクライアントとする
+ */
+pub fn client_ip(config:&ConfigFile,peer:&SocketAddr,headers:&HeaderMap)->IpAddr{
+	let peer=peer.ip().to_canonical();
+	if !is_trusted_proxy(config,&peer){
+		return peer;
+	}
+	let chain=forwarded_for(headers);
+	for addr in chain.iter().rev(){
+		if !is_trusted_proxy(config,addr){
+			return *addr;
+		}
+	}
+	chain.first().copied().unwrap_or(peer)
+}
+/**
+ * バックエンドに渡すX-Forwarded-For,X-Forwarded-Proto,X-Real-IPを設定する
+ * 信用できない接続元から来た同名ヘッダは捨てる
+ */
+pub fn set_forwarded_headers(config:&ConfigFile,peer:&SocketAddr,headers:&mut HeaderMap){
+	let peer_ip=peer.ip().to_canonical();
+	let trusted=is_trusted_proxy(config,&peer_ip);
+	let client=client_ip(config,peer,headers);
+	let mut chain=if trusted{
+		forwarded_for(headers).iter().map(|v|v.to_string()).collect()
+	}else{
+		vec![]
+	};
+	chain.push(peer_ip.to_string());
+	let proto=if trusted{
+		headers.get(X_FORWARDED_PROTO).cloned()
+	}else{
+		None
+	};
+	headers.remove(X_FORWARDED_FOR);
+	headers.remove(X_FORWARDED_PROTO);
+	headers.remove(X_REAL_IP);
+	if let Ok(v)=HeaderValue::from_str(&chain.join(", ")){
+		headers.insert(X_FORWARDED_FOR,v);
+	}
+	headers.insert(X_FORWARDED_PROTO,proto.unwrap_or(HeaderValue::from_static("http")));
+	if let Ok(v)=HeaderValue::from_str(&client.to_string()){
+		headers.insert(X_REAL_IP,v);
+	}
+}
+/**
+ * WebSocketのように元のヘッダをそのまま転送しない場合に、転送用のヘッダだけを作る
+ */
+pub fn forwarded_headers(config:&ConfigFile,peer:&SocketAddr,headers:&HeaderMap)->HeaderMap{
+	let mut forwarded=HeaderMap::new();
+	for name in [X_FORWARDED_FOR,X_FORWARDED_PROTO]{
+		for v in headers.get_all(name){
+			forwarded.append(name,v.clone());
+		}
+	}
+	set_forwarded_headers(config,peer,&mut forwarded);
+	forwarded
+}
+#[cfg(test)]
+mod tests{
+	use super::*;
+
+	fn config(trusted_proxies:&[&str])->ConfigFile{
+		serde_json::from_value(serde_json::json!({
+			"bind_addr":"0.0.0.0:3030",
+			"public_base_url":"http://localhost",
+			"prefix":"prefix",
+			"thumbnail_filter":"Lanczos3",
+			"thumbnail_quality":50.0,
+			"s3":{
+				"endpoint":"localhost",
+				"bucket":"bucket",
+				"region":"us-east-1",
+				"access_key":"",
+				"secret_key":"",
+				"timeout":5000,
+				"path_style":true,
+			},
+			"session_ttl":300,
+			"part_max_size":1024,
+			"backend":"http://localhost:3000",
+			"full_upload_limit":1024,
+			"trusted_proxies":trusted_proxies,
+		})).unwrap()
+	}
+	fn forwarded(value:&str)->HeaderMap{
+		let mut headers=HeaderMap::new();
+		headers.insert(X_FORWARDED_FOR,value.parse().unwrap());
+		headers
+	}
+	fn ip(v:&str)->IpAddr{
+		v.parse().unwrap()
+	}
+	#[test]
+	fn match_proxy_cidr(){
+		assert!(match_proxy("10.0.0.0/8",&ip("10.255.0.1")));
+		assert!(!match_proxy("10.0.0.0/8",&ip("11.0.0.1")));
+		assert!(match_proxy("192.168.0.1",&ip("192.168.0.1")));
+		assert!(!match_proxy("192.168.0.1",&ip("192.168.0.2")));
+		assert!(match_proxy("0.0.0.0/0",&ip("203.0.113.1")));
+		assert!(match_proxy("fd00::/8",&ip("fd12::1")));
+		assert!(!match_proxy("fd00::/8",&ip("2001:db8::1")));
+		//IPv4とIPv6は互いに一致しない
+		assert!(!match_proxy("::/0",&ip("10.0.0.1")));
+		assert!(!match_proxy("invalid",&ip("10.0.0.1")));
+	}
+	#[test]
+	fn spoofed_entries_left_of_trusted_hops(){
+		let config=config(&["10.0.0.0/8"]);
+		let peer="10.0.0.1:443".parse().unwrap();
+		//クライアントが自分で付けた左側の値は信用しない
+		let headers=forwarded("1.2.3.4, 198.51.100.7, 10.0.0.2");
+		assert_eq!(client_ip(&config,&peer,&headers),ip("198.51.100.7"));
+		//全て信用できる場合は一番左
+		let headers=forwarded("10.0.0.3, 10.0.0.2");
+		assert_eq!(client_ip(&config,&peer,&headers),ip("10.0.0.3"));
+		assert_eq!(client_ip(&config,&peer,&HeaderMap::new()),ip("10.0.0.1"));
+	}
+	#[test]
+	fn untrusted_peer(){
+		let config=config(&["10.0.0.0/8"]);
+		let peer="203.0.113.9:443".parse().unwrap();
+		let headers=forwarded("1.2.3.4");
+		assert_eq!(client_ip(&config,&peer,&headers),ip("203.0.113.9"));
+		let mut headers=headers;
+		headers.insert(X_REAL_IP,"1.2.3.4".parse().unwrap());
+		headers.insert(X_FORWARDED_PROTO,"https".parse().unwrap());
+		set_forwarded_headers(&config,&peer,&mut headers);
+		assert_eq!(headers.get(X_FORWARDED_FOR).unwrap(),"203.0.113.9");
+		assert_eq!(headers.get(X_REAL_IP).unwrap(),"203.0.113.9");
+		assert_eq!(headers.get(X_FORWARDED_PROTO).unwrap(),"http");
+	}
+	#[test]
+	fn ipv6_peer(){
+		let config=config(&["fd00::/8"]);
+		let peer="[fd00::1]:443".parse().unwrap();
+		let headers=forwarded("2001:db8::1, fd00::2");
+		assert_eq!(client_ip(&config,&peer,&headers),ip("2001:db8::1"));
+		let peer="[2001:db8::2]:443".parse().unwrap();
+		assert_eq!(client_ip(&config,&peer,&headers),ip("2001:db8::2"));
+	}
+	#[test]
+	fn ipv4_mapped_peer(){
+		let config=config(&["10.0.0.0/8"]);
+		let peer="[::ffff:10.0.0.1]:443".parse().unwrap();
+		let mut headers=forwarded("198.51.100.7");
+		assert_eq!(client_ip(&config,&peer,&headers),ip("198.51.100.7"));
+		set_forwarded_headers(&config,&peer,&mut headers);
+		assert_eq!(headers.get(X_FORWARDED_FOR).unwrap(),"198.51.100.7, 10.0.0.1");
+		let peer="[::ffff:203.0.113.9]:443".parse().unwrap();
+		assert_eq!(client_ip(&config,&peer,&forwarded("198.51.100.7")),ip("203.0.113.9"));
+	}
+}
@@ -0,0 +1,408 @@
+use std::{net::{IpAddr, SocketAddr}, path::PathBuf, sync::{Arc, RwLock}, time::SystemTime};
+
+use s3::Bucket;
+use serde::Serialize;
+
+use crate::{service::{id_service::IdService, upstream::UpstreamService}, ConfigFile, Context, MisskeyConfig, S3Config};
+
+pub const USAGE:&str="usage: upload_service [--config <config.json>] [--misskey-config <default.yml>]";
+//秘密情報などを環境変数で上書きする場合の接頭辞(UPLOAD_SERVICE_S3_SECRET_KEYなど)
+const ENV_PREFIX:&str="UPLOAD_SERVICE_";
+
+/**
+ * 設定の再読み込みで差し替えられる値
+ * 読み出し側はload()した時点の値を使い続けるため、処理中のリクエストには影響しない
+ */
+#[derive(Debug)]
+pub struct Reloadable<T>(Arc<RwLock<Arc<T>>>);
+impl<T> Clone for Reloadable<T>{
+	fn clone(&self) -> Self {
+		Self(self.0.clone())
+	}
+}
+impl<T> Reloadable<T>{
+	pub fn new(value:T)->Self{
+		Self(Arc::new(RwLock::new(Arc::new(value))))
+	}
+	pub fn load(&self)->Arc<T>{
+		self.0.read().unwrap_or_else(|e|e.into_inner()).clone()
+	}
+	pub fn store(&self,value:T){
+		*self.0.write().unwrap_or_else(|e|e.into_inner())=Arc::new(value);
+	}
+}
+#[derive(Clone,Debug)]
+pub struct ConfigPaths{
+	pub config:PathBuf,
+	pub misskey_config:PathBuf,
+}
+impl Default for ConfigPaths{
+	fn default() -> Self {
+		Self{
+			config:PathBuf::from(".config/config.json"),
+			misskey_config:PathBuf::from(".config/default.yml"),
+		}
+	}
+}
+impl ConfigPaths{
+	/**
+	 * コマンドライン引数から設定ファイルの場所を決める
+	 * 省略時は従来通り.config/以下を読む
+	 */
+	pub fn from_args<I:Iterator<Item=String>>(mut args:I)->Result<Self,String>{
+		let mut paths=Self::default();
+		while let Some(arg)=args.next(){
+			let target=match arg.as_str(){
+				"-c"|"--config"=>&mut paths.config,
+				"-m"|"--misskey-config"=>&mut paths.misskey_config,
+				_=>return Err(format!("unknown argument {:?}",arg)),
+			};
+			match args.next(){
+				Some(path)=>*target=PathBuf::from(path),
+				None=>return Err(format!("{} requires a path",arg)),
+			}
+		}
+		Ok(paths)
+	}
+}
+/**
+ * 設定ファイルを読み込み、環境変数で上書きしてから全項目を検証する
+ * 見つかった問題は途中で止めずに全て返す
+ */
+pub fn load(paths:&ConfigPaths)->Result<(ConfigFile,MisskeyConfig),Vec<String>>{
+	let mut errors=vec![];
+	let config=match std::fs::File::open(&paths.config){
+		Ok(f)=>match serde_json::from_reader::<_,ConfigFile>(std::io::BufReader::new(f)){
+			Ok(config)=>Some(config),
+			Err(e)=>{
+				errors.push(format!("{}: {}",paths.config.display(),e));
+				None
+			}
+		},
+		Err(e)=>{
+			errors.push(format!("{}: {}",paths.config.display(),e));
+			None
+		}
+	};
+	let misskey_config=match std::fs::File::open(&paths.misskey_config){
+		Ok(f)=>match serde_yaml::from_reader::<_,MisskeyConfig>(std::io::BufReader::new(f)){
+			Ok(config)=>Some(config),
+			Err(e)=>{
+				errors.push(format!("{}: {}",paths.misskey_config.display(),e));
+				None
+			}
+		},
+		Err(e)=>{
+			errors.push(format!("{}: {}",paths.misskey_config.display(),e));
+			None
+		}
+	};
+	let (mut config,mut misskey_config)=match (config,misskey_config){
+		(Some(config),Some(misskey_config))=>(config,misskey_config),
+		_=>return Err(errors),
+	};
+	apply_env(&mut config,&mut misskey_config,&mut errors);
+	validate(&config,&misskey_config,&mut errors);
+	if errors.is_empty(){
+		Ok((config,misskey_config))
+	}else{
+		Err(errors)
+	}
+}
+fn env(name:&str)->Option<String>{
+	std::env::var(format!("{}{}",ENV_PREFIX,name)).ok()
+}
+fn env_port(name:&str,errors:&mut Vec<String>)->Option<u16>{
+	let v=env(name)?;
+	match v.parse(){
+		Ok(port)=>Some(port),
+		Err(e)=>{
+			errors.push(format!("{}{}: {}",ENV_PREFIX,name,e));
+			None
+		}
+	}
+}
+fn apply_env(config:&mut ConfigFile,misskey_config:&mut MisskeyConfig,errors:&mut Vec<String>){
+	if let Some(v)=env("BIND_ADDR"){
+		config.bind_addr=v;
+	}
+	if let Some(v)=env("BACKEND"){
+		config.backend=v;
+	}
+	if let Some(v)=env("S3_ENDPOINT"){
+		config.s3.endpoint=v;
+	}
+	if let Some(v)=env("S3_BUCKET"){
+		config.s3.bucket=v;
+	}
+	if let Some(v)=env("S3_REGION"){
+		config.s3.region=v;
+	}
+	if let Some(v)=env("S3_ACCESS_KEY"){
+		config.s3.access_key=v;
+	}
+	if let Some(v)=env("S3_SECRET_KEY"){
+		config.s3.secret_key=v;
+	}
+	if let Some(v)=env("DB_HOST"){
+		misskey_config.db.host=v;
+	}
+	if let Some(v)=env_port("DB_PORT",errors){
+		misskey_config.db.port=v;
+	}
+	if let Some(v)=env("DB_NAME"){
+		misskey_config.db.db=v;
+	}
+	if let Some(v)=env("DB_USER"){
+		misskey_config.db.user=v;
+	}
+	if let Some(v)=env("DB_PASS"){
+		misskey_config.db.pass=v;
+	}
+	if let Some(v)=env("REDIS_HOST"){
+		misskey_config.redis.host=v;
+	}
+	if let Some(v)=env_port("REDIS_PORT",errors){
+		misskey_config.redis.port=v;
+	}
+}
+fn validate_url(name:&str,url:&str,errors:&mut Vec<String>){
+	match reqwest::Url::parse(url){
+		Ok(url) if url.scheme()=="http"||url.scheme()=="https"=>{},
+		Ok(url)=>errors.push(format!("{}: unsupported scheme {:?}",name,url.scheme())),
+		Err(e)=>errors.push(format!("{}: {}",name,e)),
+	}
+}
+fn validate_not_empty(name:&str,value:&str,errors:&mut Vec<String>){
+	if value.trim().is_empty(){
+		errors.push(format!("{}: must not be empty",name));
+	}
+}
+/**
+ * client_ip::match_proxyと同じく単一アドレスかCIDR表記
+ */
+fn validate_proxy(entry:&str,errors:&mut Vec<String>){
+	let (net,prefix)=match entry.split_once('/'){
+		Some((net,prefix))=>(net,Some(prefix)),
+		None=>(entry,None),
+	};
+	let max=match net.trim().parse::<IpAddr>(){
+		Ok(IpAddr::V4(_))=>32,
+		Ok(IpAddr::V6(_))=>128,
+		Err(e)=>{
+			errors.push(format!("trusted_proxies[{}]: {}",entry,e));
+			return;
+		}
+	};
+	if let Some(prefix)=prefix{
+		match prefix.parse::<u32>(){
+			Ok(prefix) if prefix<=max=>{},
+			_=>errors.push(format!("trusted_proxies[{}]: invalid prefix length",entry)),
+		}
+	}
+}
+fn validate(config:&ConfigFile,misskey_config:&MisskeyConfig,errors:&mut Vec<String>){
+	if let Err(e)=config.bind_addr.parse::<SocketAddr>(){
+		errors.push(format!("bind_addr: {}",e));
+	}
+	validate_url("public_base_url",&config.public_base_url,errors);
+	if let Some(url)=config.ffmpeg_base_url.as_ref(){
+		validate_url("ffmpeg_base_url",url,errors);
+	}
+	validate_url("backend",&config.backend,errors);
+	if !(0f32..=100f32).contains(&config.thumbnail_quality){
+		errors.push("thumbnail_quality: must be between 0 and 100".to_owned());
+	}
+	//S3の分割アップロードは最後以外5MB以上5GB以下
+	if config.part_max_size<5*1024*1024||config.part_max_size>5*1024*1024*1024{
+		errors.push("part_max_size: must be between 5MiB and 5GiB".to_owned());
+	}
+	if config.session_ttl==0{
+		errors.push("session_ttl: must be greater than 0".to_owned());
+	}
+	validate_not_empty("s3.endpoint",&config.s3.endpoint,errors);
+	validate_not_empty("s3.bucket",&config.s3.bucket,errors);
+	validate_not_empty("s3.access_key",&config.s3.access_key,errors);
+	validate_not_empty("s3.secret_key",&config.s3.secret_key,errors);
+	for entry in config.trusted_proxies.iter().flatten(){
+		validate_proxy(entry,errors);
+	}
+	let mut backend_names=vec![];
+	if let Some(upstream)=config.upstream.as_ref(){
+		upstream.validate(errors);
+		backend_names.extend(upstream.backend_names());
+	}
+	if backend_names.is_empty(){
+		//UpstreamService::newと同じくbackendを"default"として扱う
+		backend_names.push("default");
+	}
+	for rule in config.routes.iter().flatten(){
+		rule.validate(&backend_names,errors);
+	}
+	for rule in config.rate_limits.iter().flatten(){
+		rule.validate(errors);
+	}
+	if let Some(response_cache)=config.response_cache.as_ref(){
+		response_cache.validate(errors);
+	}
+	if let Some(compression)=config.compression.as_ref(){
+		compression.validate(errors);
+	}
+	if let Some(log_level)=config.log_level.as_ref(){
+		if let Err(e)=tracing_subscriber::EnvFilter::try_new(log_level){
+			errors.push(format!("log_level: {}",e));
+		}
+	}
+	if let Some(bind_addr)=config.metrics.as_ref().and_then(|m|m.bind_addr.as_ref()){
+		if let Err(e)=bind_addr.parse::<SocketAddr>(){
+			errors.push(format!("metrics.bind_addr: {}",e));
+		}
+	}
+	if let Err(e)=IdService::new(misskey_config){
+		errors.push(e);
+	}
+	validate_url("url",&misskey_config.url,errors);
+	validate_not_empty("db.host",&misskey_config.db.host,errors);
+	validate_not_empty("db.db",&misskey_config.db.db,errors);
+	validate_not_empty("db.user",&misskey_config.db.user,errors);
+	validate_not_empty("redis.host",&misskey_config.redis.host,errors);
+	if let Some(redis)=misskey_config.redis_for_pubsub.as_ref(){
+		validate_not_empty("redisForPubsub.host",&redis.host,errors);
+	}
+}
+pub fn build_bucket(config:&S3Config)->Result<Box<Bucket>,String>{
+	let credentials=s3::creds::Credentials::new(Some(&config.access_key),Some(&config.secret_key),None,None,None).map_err(|e|format!("s3 credentials: {:?}",e))?;
+	let bucket=s3::Bucket::new(
+		&config.bucket,
+		s3::Region::Custom {
+			region: config.region.to_owned(),
+			endpoint: config.endpoint.to_owned(),
+		},
+		credentials,
+	).map_err(|e|format!("s3 bucket: {:?}",e))?;
+	Ok(if config.path_style{
+		bucket.with_path_style()
+	}else{
+		bucket
+	})
+}
+fn changed<T:Serialize>(a:&T,b:&T)->bool{
+	serde_json::to_value(a).ok()!=serde_json::to_value(b).ok()
+}
+/**
+ * 再起動が必要な項目は変更を警告して現在の値に戻す
+ * 戻さないと読み込んだ設定と実際の動作が食い違う
+ */
+fn keep_current<T:Serialize+Clone>(name:&str,new:&mut T,current:&T){
+	if changed(new,current){
+		tracing::warn!("reload: {} is changed but requires restart",name);
+		*new=current.clone();
+	}
+}
+/**
+ * 設定ファイルを読み直して差し替える
+ * 検証に失敗した場合は現在の設定を使い続ける
+ */
+pub fn reload(ctx:&Context,paths:&ConfigPaths){
+	let (mut config,mut misskey_config)=match load(paths){
+		Ok(v)=>v,
+		Err(errors)=>{
+			for e in errors{
+				tracing::error!("reload: {}",e);
+			}
+			tracing::warn!("reload: keep current configuration");
+			return;
+		}
+	};
+	let current=ctx.config.load();
+	let current_misskey=ctx.misskey_config.load();
+	let bucket=if changed(&config.s3,&current.s3){
+		match build_bucket(&config.s3){
+			Ok(bucket)=>Some(bucket),
+			Err(e)=>{
+				tracing::error!("reload: {}",e);
+				tracing::warn!("reload: keep current configuration");
+				return;
+			}
+		}
+	}else{
+		None
+	};
+	//待ち受けや接続を作り直す必要がある項目は再起動するまで反映されない
+	keep_current("bind_addr",&mut config.bind_addr,&current.bind_addr);
+	keep_current("prefix",&mut config.prefix,&current.prefix);
+	keep_current("public_base_url",&mut config.public_base_url,&current.public_base_url);
+	keep_current("log_level",&mut config.log_level,&current.log_level);
+	keep_current("metrics",&mut config.metrics,&current.metrics);
+	keep_current("response_cache",&mut config.response_cache,&current.response_cache);
+	keep_current("id",&mut misskey_config.id,&current_misskey.id);
+	//イベントを購読するチャンネル名はurlから決まる
+	keep_current("url",&mut misskey_config.url,&current_misskey.url);
+	keep_current("db",&mut misskey_config.db,&current_misskey.db);
+	keep_current("redis",&mut misskey_config.redis,&current_misskey.redis);
+	keep_current("redisForPubsub",&mut misskey_config.redis_for_pubsub,&current_misskey.redis_for_pubsub);
+	let upstream_changed=changed(&config.backend,&current.backend)||changed(&config.upstream,&current.upstream);
+	if let Some(bucket)=bucket{
+		ctx.bucket.store(*bucket);
+	}
+	if upstream_changed{
+		let upstream_service=UpstreamService::new(&config);
+		upstream_service.spawn_health_check(ctx.client.clone());
+		ctx.upstream_service.store(upstream_service);
+	}
+	ctx.config.store(config);
+	ctx.misskey_config.store(misskey_config);
+	tracing::info!("configuration reloaded");
+}
+fn modified_times(paths:&ConfigPaths)->(Option<SystemTime>,Option<SystemTime>){
+	let modified=|path:&PathBuf|std::fs::metadata(path).and_then(|m|m.modified()).ok();
+	(modified(&paths.config),modified(&paths.misskey_config))
+}
+/**
+ * SIGHUPを受けるか、config_watch_interval毎に確認した設定ファイルの更新日時が変わったら再読み込みする
+ */
+pub fn spawn_watcher(ctx:Context,paths:ConfigPaths){
+	use futures::{future::FutureExt,pin_mut};
+	tokio::spawn(async move{
+		#[cfg(unix)]
+		let mut hangup=match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()){
+			Ok(signal)=>Some(signal),
+			Err(e)=>{
+				tracing::error!("failed to install SIGHUP handler {:?}",e);
+				None
+			}
+		};
+		let mut last_modified=modified_times(&paths);
+		loop{
+			let interval=ctx.config.load().config_watch_interval.unwrap_or(0);
+			let poll=async{
+				if interval==0{
+					std::future::pending::<()>().await;
+				}
+				tokio::time::sleep(std::time::Duration::from_secs(interval)).await;
+			}.fuse();
+			let signal=async{
+				#[cfg(unix)]
+				if let Some(hangup)=hangup.as_mut(){
+					hangup.recv().await;
+					return;
+				}
+				std::future::pending::<()>().await;
+			}.fuse();
+			pin_mut!(poll,signal);
+			futures::select!{
+				_ = signal => {
+					tracing::info!("SIGHUP received");
+				},
+				_ = poll => {
+					if modified_times(&paths)==last_modified{
+						continue;
+					}
+					tracing::info!("configuration file is modified");
+				},
+			}
+			last_modified=modified_times(&paths);
+			reload(&ctx,&paths);
+		}
+	});
+}
@@ -0,0 +1,171 @@
+use axum::{http::{HeaderMap, StatusCode}, response::IntoResponse};
+
+use crate::service::drive::RegisterPreflightError;
+
+/**
+ * Misskeyと同じ形式({"error":{"message","code","id","kind"}})で返すAPIエラー
+ * Misskey本体に対応するエラーがあるものはcode,idを合わせる
+ */
+#[derive(Debug)]
+pub enum ApiError{
+	CredentialRequired,
+	AuthenticationFailed,
+	InvalidParam(String),
+	FileRequired,
+	RateLimitExceeded{
+		retry_after_ms:u64,
+	},
+	NoFreeSpace,
+	MaxFileSizeExceeded,
+	InvalidFileName,
+	NoSuchFolder,
+	//part_numberはpartial-uploadで指定された番号
+	PartUploadFailed{
+		part_number:u32,
+	},
+	PartUploadTimeout{
+		part_number:u32,
+	},
+	InternalError,
+}
+impl ApiError{
+	/**
+	 * 内部エラーの詳細はログにだけ残す
+	 */
+	pub fn internal<E:std::fmt::Debug>(e:E)->Self{
+		tracing::error!("{:?}",e);
+		Self::InternalError
+	}
+	pub fn code(&self)->&'static str{
+		match self{
+			Self::CredentialRequired=>"CREDENTIAL_REQUIRED",
+			Self::AuthenticationFailed=>"AUTHENTICATION_FAILED",
+			Self::InvalidParam(_)=>"INVALID_PARAM",
+			Self::FileRequired=>"FILE_REQUIRED",
+			Self::RateLimitExceeded{..}=>"RATE_LIMIT_EXCEEDED",
+			Self::NoFreeSpace=>"NO_FREE_SPACE",
+			Self::MaxFileSizeExceeded=>"MAX_FILE_SIZE_EXCEEDED",
+			Self::InvalidFileName=>"INVALID_FILE_NAME",
+			Self::NoSuchFolder=>"NO_SUCH_FOLDER",
+			Self::PartUploadFailed{..}=>"PART_UPLOAD_FAILED",
+			Self::PartUploadTimeout{..}=>"PART_UPLOAD_TIMEOUT",
+			Self::InternalError=>"INTERNAL_ERROR",
+		}
+	}
+	pub fn id(&self)->&'static str{
+		match self{
+			Self::CredentialRequired=>"1384574d-a912-4b81-8601-c7b1c4085df1",
+			Self::AuthenticationFailed=>"b0a7f5f8-dc2f-4171-b91f-de88ad238e14",
+			Self::InvalidParam(_)=>"3d81ceae-475f-4600-b2a8-2bc116157532",
+			Self::FileRequired=>"4267801e-70d1-416a-b011-4ee502885d8b",
+			Self::RateLimitExceeded{..}=>"d5826d14-3982-4d2e-8011-b9e9f02499ef",
+			Self::NoFreeSpace=>"d08dbc37-a6a9-463a-8c47-96c32ab5f064",
+			Self::MaxFileSizeExceeded=>"b9d8c348-33f0-4673-b9a9-5d4da058977a",
+			Self::InvalidFileName=>"f449b209-0c60-4e51-84d5-29486263bfd4",
+			Self::NoSuchFolder=>"ea8fb7a5-af77-4a08-b608-c0218176cd73",
+			Self::PartUploadFailed{..}=>"7c1f4a3e-5b2d-4e8a-9f60-2d8c3b9e1a47",
+			Self::PartUploadTimeout{..}=>"e2a9d6b1-8c4f-4f3a-a5e7-6b0d1c9f8e25",
+			Self::InternalError=>"5d37dbcb-891e-41ca-a3d6-e690c97775ac",
+		}
+	}
+	pub fn message(&self)->&'static str{
+		match self{
+			Self::CredentialRequired=>"Credential required.",
+			Self::AuthenticationFailed=>"Authentication failed. Please ensure your token is correct.",
+			Self::InvalidParam(_)=>"Invalid param.",
+			Self::FileRequired=>"File required.",
+			Self::RateLimitExceeded{..}=>"Rate limit exceeded. Please try again later.",
+			Self::NoFreeSpace=>"Cannot upload the file because you have no free space of drive.",
+			Self::MaxFileSizeExceeded=>"Cannot upload the file because it exceeds the maximum file size.",
+			Self::InvalidFileName=>"Invalid file name.",
+			Self::NoSuchFolder=>"No such folder.",
+			Self::PartUploadFailed{..}=>"Failed to store an uploaded part.",
+			Self::PartUploadTimeout{..}=>"Timed out waiting for an uploaded part to be stored.",
+			Self::InternalError=>"Internal error occurred. Please contact us if the error persists.",
+		}
+	}
+	pub fn status(&self)->StatusCode{
+		match self{
+			Self::CredentialRequired=>StatusCode::UNAUTHORIZED,
+			Self::AuthenticationFailed=>StatusCode::UNAUTHORIZED,
+			Self::RateLimitExceeded{..}=>StatusCode::TOO_MANY_REQUESTS,
+			Self::MaxFileSizeExceeded=>StatusCode::PAYLOAD_TOO_LARGE,
+			Self::PartUploadFailed{..}=>StatusCode::BAD_GATEWAY,
+			Self::PartUploadTimeout{..}=>StatusCode::GATEWAY_TIMEOUT,
+			Self::InternalError=>StatusCode::INTERNAL_SERVER_ERROR,
+			_=>StatusCode::BAD_REQUEST,
+		}
+	}
+	/**
+	 * {"error":...}の中身
+	 */
+	pub fn to_json(&self)->serde_json::Value{
+		let kind=if self.status().is_server_error(){
+			"server"
+		}else{
+			"client"
+		};
+		let mut error=serde_json::json!({
+			"message":self.message(),
+			"code":self.code(),
+			"id":self.id(),
+			"kind":kind,
+		});
+		match self{
+			Self::InvalidParam(reason)=>{
+				error["info"]=serde_json::json!({
+					"reason":reason,
+				});
+			},
+			Self::PartUploadFailed{part_number}|Self::PartUploadTimeout{part_number}=>{
+				error["info"]=serde_json::json!({
+					"partNumber":part_number,
+				});
+			},
+			_=>{},
+		}
+		error
+	}
+}
+impl IntoResponse for ApiError{
+	fn into_response(self) -> axum::response::Response {
+		let error=self.to_json();
+		let mut header=HeaderMap::new();
+		header.insert(axum::http::header::CONTENT_TYPE,"application/json".parse().unwrap());
+		header.insert(axum::http::header::ACCESS_CONTROL_ALLOW_ORIGIN,"*".parse().unwrap());
+		if let Self::RateLimitExceeded{retry_after_ms}=&self{
+			header.insert(axum::http::header::RETRY_AFTER,retry_after_ms.div_ceil(1000).into());
+		}
+		let body=serde_json::json!({
+			"error":error,
+		});
+		(self.status(),header,body.to_string()).into_response()
+	}
+}
+impl From<RegisterPreflightError> for ApiError{
+	fn from(e: RegisterPreflightError) -> Self {
+		match e{
+			RegisterPreflightError::InternalServerError=>Self::InternalError,
+			RegisterPreflightError::FileSizeLimitOver=>Self::MaxFileSizeExceeded,
+			RegisterPreflightError::ExtTooLarge=>Self::InvalidFileName,
+			RegisterPreflightError::BadExt=>Self::InvalidFileName,
+			RegisterPreflightError::NoFreeSpace=>Self::NoFreeSpace,
+			RegisterPreflightError::FolderNotFound=>Self::NoSuchFolder,
+		}
+	}
+}
+impl From<s3::error::S3Error> for ApiError{
+	fn from(e: s3::error::S3Error) -> Self {
+		Self::internal(e)
+	}
+}
+impl From<redis::RedisError> for ApiError{
+	fn from(e: redis::RedisError) -> Self {
+		Self::internal(e)
+	}
+}
+impl From<diesel::result::Error> for ApiError{
+	fn from(e: diesel::result::Error) -> Self {
+		Self::internal(e)
+	}
+}
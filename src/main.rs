@@ -1,15 +1,22 @@
-use std::{io::Write, net::SocketAddr, sync::Arc};
+use std::{io::Write, net::SocketAddr};
 
-use axum::{http::StatusCode, response::{IntoResponse, Response}, Router};
+use api::{compression::CompressionConfig, route_table::RouteRule};
+use axum::Router;
 use diesel_async::AsyncPgConnection;
 use redis::aio::MultiplexedConnection;
-use service::{announcement::AnnouncementService, drive::DriveService, event::EventService, file_meta::FileMetaService, id_service::IdService, meta::MetaService, role::RoleService, user::UserService};
+use service::{announcement::AnnouncementService, drive::DriveService, event::EventService, file_meta::FileMetaService, id_service::IdService, meta::MetaService, metrics::{MetricsConfig, MetricsService}, quota::QuotaService, rate_limit::{RateLimitRule, RateLimitService}, response_cache::{ResponseCacheConfig, ResponseCacheService}, role::RoleService, upstream::{UpstreamConfig, UpstreamService}, user::UserService};
+use config::Reloadable;
 use s3::Bucket;
 use serde::{Deserialize, Serialize};
+use futures::future::{FusedFuture, FutureExt};
+use tokio_util::{sync::CancellationToken, task::TaskTracker};
 mod browsersafe;
+mod client_ip;
 mod service;
 mod models;
 mod api;
+mod config;
+mod error;
 
 #[derive(Clone,Debug,Serialize,Deserialize)]
 pub struct ConfigFile{
@@ -25,6 +32,30 @@ pub struct ConfigFile{
 	part_max_size:u64,
 	backend:String,
 	full_upload_limit: u32,
+	trusted_proxies:Option<Vec<String>>,
+	upstream:Option<UpstreamConfig>,
+	routes:Option<Vec<RouteRule>>,
+	rate_limits:Option<Vec<RateLimitRule>>,
+	response_cache:Option<ResponseCacheConfig>,
+	compression:Option<CompressionConfig>,
+	//tracing_subscriber::EnvFilterの書式("info","info,upload_service=debug"など)
+	log_level:Option<String>,
+	metrics:Option<MetricsConfig>,
+	//readyzでHEADするストレージ上のキー(prefixからの相対)
+	health_check_key:Option<String>,
+	//設定ファイルの更新を確認する間隔(秒)、0か省略時はSIGHUPでのみ再読み込みする
+	config_watch_interval:Option<u64>,
+	//終了シグナルを受けてから処理中のアップロードなどを待つ秒数
+	shutdown_grace_period:Option<u64>,
+	//finish-uploadでパートの保存完了を待つ秒数
+	part_wait_timeout:Option<u64>,
+	//WebSocketの無通信を切断するまでの秒数、半分の間隔でPingを送る。0で無効
+	websocket_idle_timeout:Option<u64>,
+	//パートを直接S3に送る分割アップロードの有効期限(秒)、省略時は受け付けない
+	//バケットのCORSでPUTとETagヘッダーの公開を許可しておく必要がある
+	direct_upload_ttl:Option<u64>,
+	//アップロード用チケットの有効期限の上限(秒)、省略時は発行しない
+	upload_ticket_max_ttl:Option<u64>,
 }
 
 #[derive(Clone,Debug,Serialize,Deserialize)]
@@ -86,9 +117,9 @@ impl DBConfig{
 }
 #[derive(Clone,Debug)]
 pub struct Context{
-	bucket:Box<Bucket>,
-	config:Arc<ConfigFile>,
-	misskey_config:Arc<MisskeyConfig>,
+	bucket:Reloadable<Bucket>,
+	config:Reloadable<ConfigFile>,
+	misskey_config:Reloadable<MisskeyConfig>,
 	redis:MultiplexedConnection,
 	client:reqwest::Client,
 	role_service:RoleService,
@@ -97,6 +128,14 @@ pub struct Context{
 	raw_db:DataBase,
 	file_service: FileMetaService,
 	user_service: UserService,
+	upstream_service: Reloadable<UpstreamService>,
+	rate_limit_service: RateLimitService,
+	quota_service: QuotaService,
+	metrics: MetricsService,
+	response_cache: ResponseCacheService,
+	//終了時に完了を待つバックグラウンド処理
+	tasks: TaskTracker,
+	shutdown: CancellationToken,
 }
 #[derive(Clone, Copy,Debug,Serialize,Deserialize)]
 enum FilterType{
@@ -152,9 +191,23 @@ async fn shutdown_signal() {
 		_ = terminate => {},
 	}
 }
+/**
+ * 起動時の致命的なエラーを記録して終了する
+ */
+fn exit_with_error<E:std::fmt::Debug>(message:&str,e:E)->!{
+	tracing::error!("{}: {:?}",message,e);
+	std::process::exit(1);
+}
 fn main() {
-	let config_path=".config/config.json";
-	if !std::path::Path::new(&config_path).exists(){
+	let paths=match config::ConfigPaths::from_args(std::env::args().skip(1)){
+		Ok(paths)=>paths,
+		Err(e)=>{
+			eprintln!("{}\n{}",e,config::USAGE);
+			std::process::exit(2);
+		}
+	};
+	let config_path=&paths.config;
+	if !config_path.exists(){
 		let default_config=ConfigFile{
 			bind_addr: "0.0.0.0:12200".to_owned(),
 			public_base_url:"https://files.example.com/".to_owned(),
@@ -176,49 +229,111 @@ fn main() {
 			},
 			session_ttl: 300,
 			backend: "http://localhost:3000".to_owned(),
+			trusted_proxies:Some(vec!["127.0.0.1".to_owned(),"::1".to_owned()]),
+			upstream:None,
+			routes:None,
+			rate_limits:Some(vec![
+				RateLimitRule{
+					path:"/api/drive/files/create".to_owned(),
+					methods:None,
+					max:120,
+					duration:60*60*1000,
+				},
+				RateLimitRule{
+					path:"/api/drive/files/multipart/*".to_owned(),
+					methods:None,
+					max:600,
+					duration:60*1000,
+				},
+			]),
+			response_cache:None,
+			compression:Some(CompressionConfig::default()),
+			log_level:Some("info".to_owned()),
+			metrics:None,
+			health_check_key:Some(".healthcheck".to_owned()),
+			config_watch_interval:Some(5),
+			shutdown_grace_period:Some(30),
+			part_wait_timeout:Some(600),
+			websocket_idle_timeout:Some(60),
+			direct_upload_ttl:None,
+			upload_ticket_max_ttl:Some(24*60*60),
 		};
 		let default_config=serde_json::to_string_pretty(&default_config).unwrap();
-		std::fs::File::create(&config_path).expect("create default config.json").write_all(default_config.as_bytes()).unwrap();
+		if let Err(e)=std::fs::File::create(&config_path).and_then(|mut f|f.write_all(default_config.as_bytes())){
+			eprintln!("{}: {}",config_path.display(),e);
+			std::process::exit(1);
+		}
 	}
-	let misskey_config:MisskeyConfig=serde_yaml::from_reader(std::fs::File::open(&".config/default.yml").unwrap()).unwrap();
-	let misskey_config=Arc::new(misskey_config);
-	let file_service=FileMetaService::new();
-	let config:ConfigFile=serde_json::from_reader(std::fs::File::open(&config_path).unwrap()).unwrap();
-	let config=Arc::new(config);
-	let bucket = s3::Bucket::new(
-		&config.s3.bucket,
-		s3::Region::Custom {
-			region: config.s3.region.to_owned(),
-			endpoint: config.s3.endpoint.to_owned(),
-		},
-		s3::creds::Credentials::new(Some(&config.s3.access_key),Some(&config.s3.secret_key),None,None,None).unwrap(),
-	).unwrap();
-	let bucket=if config.s3.path_style{
-		bucket.with_path_style()
-	}else{
-		bucket
+	//ログの設定も設定ファイルにあるため、ここでの問題は標準エラーに出す
+	let (config,misskey_config)=match config::load(&paths){
+		Ok(v)=>v,
+		Err(errors)=>{
+			eprintln!("invalid configuration:");
+			for e in errors{
+				eprintln!("  {}",e);
+			}
+			std::process::exit(1);
+		}
+	};
+	let log_filter=tracing_subscriber::EnvFilter::new(config.log_level.as_deref().unwrap_or("info"));
+	tracing_subscriber::fmt().json().with_env_filter(log_filter).with_current_span(true).with_span_list(false).with_file(true).with_line_number(true).init();
+	let misskey_config_handle=Reloadable::new(misskey_config);
+	let misskey_config=misskey_config_handle.load();
+	let metrics=MetricsService::new();
+	let file_service=FileMetaService::new(metrics.clone());
+	let config_handle=Reloadable::new(config);
+	let config=config_handle.load();
+	let bucket=match config::build_bucket(&config.s3){
+		Ok(bucket)=>Reloadable::new(*bucket),
+		Err(e)=>exit_with_error("storage",e),
+	};
+	let redis=match redis::Client::open(misskey_config.redis.to_url()){
+		Ok(redis)=>redis,
+		Err(e)=>exit_with_error("redis",e),
+	};
+	let redis_for_pubsub=misskey_config.redis_for_pubsub.as_ref().map(|redis_for_pubsub|match redis::Client::open(redis_for_pubsub.to_url()){
+		Ok(redis)=>redis,
+		Err(e)=>exit_with_error("redisForPubsub",e),
+	});
+	let pubsub_client=redis_for_pubsub.clone().unwrap_or(redis.clone());
+	let rt=match tokio::runtime::Builder::new_multi_thread().enable_all().build(){
+		Ok(rt)=>rt,
+		Err(e)=>exit_with_error("tokio runtime",e),
 	};
-	let redis=redis::Client::open(misskey_config.redis.to_url()).unwrap();
-	let redis_for_pubsub=misskey_config.redis_for_pubsub.as_ref().map(|redis_for_pubsub|redis::Client::open(redis_for_pubsub.to_url()).unwrap());
-	let rt=tokio::runtime::Builder::new_multi_thread().enable_all().build().unwrap();
 	rt.block_on(async{
-		let redis=redis.get_multiplexed_tokio_connection().await.unwrap();
+		let redis=match redis.get_multiplexed_tokio_connection().await{
+			Ok(redis)=>redis,
+			Err(e)=>exit_with_error(&format!("connect redis {}:{}",misskey_config.redis.host,misskey_config.redis.port),e),
+		};
 		let redis_for_pubsub=match redis_for_pubsub{
 			Some(redis_for_pubsub)=>redis_for_pubsub.get_multiplexed_tokio_connection().await.ok(),
 			None=>None
 		};
-		let db=DataBase::open(&misskey_config.db.to_url()).await.unwrap();
-		let id_service=IdService::new(&misskey_config);
+		let db=match DataBase::open(&misskey_config.db.to_url()).await{
+			Ok(db)=>db,
+			Err(e)=>exit_with_error(&format!("connect database {}:{}/{}",misskey_config.db.host,misskey_config.db.port,misskey_config.db.db),e),
+		};
+		let id_service=match IdService::new(&misskey_config){
+			Ok(id_service)=>id_service,
+			Err(e)=>exit_with_error("id",e),
+		};
 		let meta_service=MetaService::new(db.clone());
 		let role_service=RoleService::new(db.clone(),meta_service.clone());
 		let announcement_service=AnnouncementService::new(db.clone());
 		let user_service=UserService::new(redis.clone(),db.clone(),id_service.clone(),role_service.clone(),announcement_service);
-		let event_service=EventService::new(redis_for_pubsub.clone().unwrap_or(redis.clone()),misskey_config.clone());
-		let drive_service=DriveService::new(misskey_config.clone(),db.clone(),meta_service,role_service.clone(),id_service,user_service.clone(),event_service.clone());
+		let event_service=EventService::new(redis_for_pubsub.clone().unwrap_or(redis.clone()),misskey_config_handle.clone());
+		event_service.spawn_subscriber(pubsub_client);
+		let drive_service=DriveService::new(misskey_config_handle.clone(),db.clone(),redis.clone(),meta_service,role_service.clone(),id_service,user_service.clone(),event_service.clone());
 		let client=reqwest::Client::new();
+		let rate_limit_service=RateLimitService::new(redis.clone());
+		let quota_service=QuotaService::new(redis.clone());
+		let response_cache=ResponseCacheService::new(config.response_cache.clone(),redis.clone());
+		let upstream_service=UpstreamService::new(&config);
+		upstream_service.spawn_health_check(client.clone());
+		let upstream_service=Reloadable::new(upstream_service);
 		let arg_tup=Context{
 			bucket,
-			config,
+			config:config_handle,
 			redis,
 			client,
 			role_service,
@@ -227,14 +342,76 @@ fn main() {
 			file_service,
 			raw_db:db,
 			user_service,
-			misskey_config,
+			misskey_config:misskey_config_handle,
+			upstream_service,
+			rate_limit_service,
+			quota_service,
+			metrics,
+			response_cache,
+			tasks:TaskTracker::new(),
+			shutdown:CancellationToken::new(),
+		};
+		config::spawn_watcher(arg_tup.clone(),paths.clone());
+		let http_addr:SocketAddr = match config.bind_addr.parse(){
+			Ok(addr)=>addr,
+			Err(e)=>exit_with_error("bind_addr",e),
 		};
-		let http_addr:SocketAddr = arg_tup.config.bind_addr.parse().unwrap();
 		let app = Router::new();
 		let app=api::route(&arg_tup,app);
-		let listener = tokio::net::TcpListener::bind(&http_addr).await.unwrap();
-		println!("server loaded");
-		axum::serve(listener,app.into_make_service_with_connect_info::<SocketAddr>()).with_graceful_shutdown(shutdown_signal()).await.unwrap();
+		if let Some(metrics_addr)=config.metrics.as_ref().and_then(|m|m.bind_addr.as_ref()){
+			let metrics_addr:SocketAddr=match metrics_addr.parse(){
+				Ok(addr)=>addr,
+				Err(e)=>exit_with_error("metrics.bind_addr",e),
+			};
+			let ctx=arg_tup.clone();
+			let metrics_app=Router::new().route("/metrics",axum::routing::get(move||api::metrics::metrics(ctx.clone())));
+			let listener=match tokio::net::TcpListener::bind(&metrics_addr).await{
+				Ok(listener)=>listener,
+				Err(e)=>exit_with_error(&format!("listen {}",metrics_addr),e),
+			};
+			tokio::spawn(async move{
+				if let Err(e)=axum::serve(listener,metrics_app).await{
+					tracing::error!("{:?}",e);
+				}
+			});
+		}
+		let listener = match tokio::net::TcpListener::bind(&http_addr).await{
+			Ok(listener)=>listener,
+			Err(e)=>exit_with_error(&format!("listen {}",http_addr),e),
+		};
+		tracing::info!("server loaded");
+		let shutdown=arg_tup.shutdown.clone();
+		let server=axum::serve(listener,app.into_make_service_with_connect_info::<SocketAddr>()).with_graceful_shutdown(async move{
+			shutdown_signal().await;
+			shutdown.cancel();
+		});
+		let mut server=tokio::spawn(async move{
+			server.await
+		}).fuse();
+		let cancelled=arg_tup.shutdown.cancelled().fuse();
+		futures::pin_mut!(cancelled);
+		futures::select!{
+			res = server => match res{
+				Ok(Ok(_))=>{},
+				Ok(Err(e))=>exit_with_error("serve",e),
+				Err(e)=>exit_with_error("serve",e),
+			},
+			_ = cancelled => {},
+		}
+		//新規の接続は受け付けずに、処理中のリクエストと分割アップロードのパートなどの完了を待つ
+		let grace=arg_tup.config.load().shutdown_grace_period.unwrap_or(30);
+		tracing::info!("shutting down, waiting up to {}s for {} background tasks",grace,arg_tup.tasks.len());
+		arg_tup.tasks.close();
+		let drain=async{
+			if !server.is_terminated(){
+				let _=server.await;
+			}
+			arg_tup.tasks.wait().await;
+		};
+		if tokio::time::timeout(std::time::Duration::from_secs(grace),drain).await.is_err(){
+			tracing::warn!("grace period elapsed, abandon {} background tasks",arg_tup.tasks.len());
+		}
+		tracing::info!("shutdown complete");
 	});
 }
 #[derive(Debug,Serialize, Deserialize)]
@@ -255,6 +432,18 @@ pub struct UploadSession{
 	name: String,
 	sensitive_threshold: f32,
 	skip_sensitive_detection: bool,
+	//パートをクライアントから直接S3に送る
+	#[serde(default)]
+	direct:bool,
+	//preflightで申告されたサイズ、超えるパートは拒否する
+	#[serde(default)]
+	declared_length:Option<u64>,
+	//ファイル単位の容量制限(バイト)
+	#[serde(default)]
+	file_size_limit:Option<u64>,
+	//QuotaServiceで予約済みのバイト数
+	#[serde(default)]
+	reserved:u64,
 }
 pub(crate) fn md5_ontext_into_raw(ctx:md5::Context)->String{
 	let ptr=Box::leak(Box::new(ctx));
@@ -277,7 +466,7 @@ pub(crate) fn md5_ontext_from_raw(s:&String)->md5::Context{
 	*s
 }
 impl Context{
-	pub async fn upload_session(&mut self,authorization: Option<&axum::http::HeaderValue>,del:bool)->Result<(UploadSession,String),Response>{
+	pub async fn upload_session(&mut self,authorization: Option<&axum::http::HeaderValue>,del:bool)->Result<(UploadSession,String),error::ApiError>{
 		let session=match authorization.map(|v|v.to_str().map(|s|{
 			if s.starts_with("Bearer "){
 				Some(&s["Bearer ".len()..])
@@ -301,18 +490,21 @@ impl Context{
 					self.redis.get::<&String,String>(&format!("multipartUpload:{}",sid)).await.map(|v|serde_json::from_str::<UploadSession>(&v))
 				};
 				match res{
-					Ok(Ok(s))=>Ok((s,sid)),
-					Ok(Err(_))=>{
-						return Err((StatusCode::INTERNAL_SERVER_ERROR).into_response())
+					Ok(Ok(s))=>{
+						api::access_log::record_user(&s.user_id);
+						Ok((s,sid))
+					},
+					Ok(Err(e))=>{
+						return Err(error::ApiError::internal(e))
 					},
 					_=>{
-						return Err((StatusCode::FORBIDDEN).into_response())
+						return Err(error::ApiError::AuthenticationFailed)
 					},
 				}
 			},
 			e=>{
-				eprintln!("{}:{} {:?}",file!(),line!(),e);
-				return Err((StatusCode::BAD_REQUEST).into_response())
+				tracing::debug!("{:?}",e);
+				return Err(error::ApiError::CredentialRequired)
 			}
 		};
 		session
@@ -334,7 +526,7 @@ impl DataBase{
 		match self.0.get().await{
 			Ok(c)=>Some(c),
 			Err(e)=>{
-				eprintln!("DB Error {:?}",e);
+				tracing::error!("DB Error {:?}",e);
 				None
 			}
 		}
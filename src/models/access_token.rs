@@ -48,7 +48,7 @@ impl MiAccessToken{
 			use self::access_token::dsl::access_token;
 			use self::access_token::dsl::*;
 			access_token.filter(token.eq(token_id)).select(MiAccessToken::as_select()).first(con).await.map_err(|e|{
-				eprintln!("{:?}",e);
+				tracing::error!("{:?}",e);
 			})
 		}.ok()?;
 		Some(res)
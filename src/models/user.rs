@@ -51,7 +51,7 @@ impl MiUser{
 			use self::user::dsl::user;
 			use self::user::dsl::*;
 			user.filter(id.eq(user_id)).select(MiUser::as_select()).first(con).await.map_err(|e|{
-				eprintln!("{:?}",e);
+				tracing::error!("{:?}",e);
 			})
 		}.ok()?;
 		Some(res)
@@ -61,7 +61,7 @@ impl MiUser{
 			use self::user::dsl::user;
 			use self::user::dsl::*;
 			user.filter(token.eq(user_token)).select(MiUser::as_select()).first(con).await.map_err(|e|{
-				eprintln!("{:?}",e);
+				tracing::error!("{:?}",e);
 			})
 		}.ok()?;
 		Some(res)
@@ -27,7 +27,7 @@ impl MiUserMemo{
 			use self::user_memo::dsl::user_memo;
 			use self::user_memo::dsl::*;
 			user_memo.filter(userId.eq(user_id)).filter(targetUserId.eq(target_user_id)).select(Self::as_select()).first(con).await.map_err(|e|{
-				eprintln!("{:?}",e);
+				tracing::error!("{:?}",e);
 			})
 		}.ok()?;
 		Some(res)
@@ -25,7 +25,7 @@ impl MiUserNotePining{
 			use self::user_note_pining::dsl::user_note_pining;
 			use self::user_note_pining::dsl::*;
 			user_note_pining.filter(userId.eq(user_id)).order(id.desc()).select(Self::as_select()).load(con).await.map_err(|e|{
-				eprintln!("{:?}",e);
+				tracing::error!("{:?}",e);
 			})
 		}.ok()?;
 		Some(res)
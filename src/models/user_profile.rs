@@ -57,7 +57,7 @@ impl MiUserProfile{
 			use self::user_profile::dsl::user_profile;
 			use self::user_profile::dsl::*;
 			user_profile.filter(userId.eq(user_id)).select(MiUserProfile::as_select()).first(con).await.map_err(|e|{
-				eprintln!("{:?}",e);
+				tracing::error!("{:?}",e);
 			})
 		}.ok()?;
 		Some(res)
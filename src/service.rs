@@ -6,3 +6,8 @@ pub mod event;
 pub mod user;
 pub mod announcement;
 pub mod file_meta;
+pub mod upstream;
+pub mod rate_limit;
+pub mod response_cache;
+pub mod metrics;
+pub mod quota;
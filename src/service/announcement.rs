@@ -29,7 +29,7 @@ impl AnnouncementService{
 			.filter(forExistingUsers.eq(false).or(id.gt(user_id)))
 			.filter(diesel::dsl::not(id.eq_any(target_ids)))
 			.load(&mut con).await.map_err(|e|{
-			eprintln!("{:?}",e);
+			tracing::error!("{:?}",e);
 		}).ok();
 		res
 	}
@@ -1,20 +1,31 @@
-use std::{borrow::Cow, str::FromStr, sync::Arc};
+use std::{borrow::Cow, str::FromStr};
 
-use crate::{models::{self, drive_file::{FileProperties, MiDriveFile}, drive_folder::MiDriveFolder, meta::SensitiveMediaDetection, user::MiUser, user_profile::MiUserProfile}, service::{self, event::{DriveEventType, MainEventType}}, DBConnection, DataBase, MisskeyConfig};
+use redis::aio::MultiplexedConnection;
+
+use crate::{config::Reloadable, models::{self, drive_file::{FileProperties, MiDriveFile}, drive_folder::MiDriveFolder, meta::SensitiveMediaDetection, user::MiUser, user_profile::MiUserProfile}, service::{self, event::{DriveEventType, MainEventType}}, DBConnection, DataBase, MisskeyConfig};
 
 use super::{event::EventService, id_service::IdService, meta::MetaService, role::RoleService, user::UserService};
 
+//sha256から探せるようにしておく秒数
+const SHA256_INDEX_TTL:u64=30*24*60*60;
+
 #[derive(Clone,Debug)]
 pub struct RegisterPreflightResult{
 	pub skip_sensitive_detection: bool,
 	pub sensitive_threshold: f32,
 	pub enable_sensitive_media_detection_for_videos: bool,
 	pub detected_name: String,
+	//ドライブの空き容量(バイト)、制限が無い場合はNone
+	pub free_space: Option<i64>,
+	//ファイル単位の容量制限(バイト)、制限が無い場合はNone
+	pub file_size_limit: Option<i64>,
 }
 #[derive(Clone,Debug)]
 pub struct DriveService{
-	config:Arc<MisskeyConfig>,
+	config:Reloadable<MisskeyConfig>,
 	db:DataBase,
+	//sha256からファイルIDへの索引
+	redis:MultiplexedConnection,
 	meta_service:MetaService,
 	role_service:RoleService,
 	id_service:IdService,
@@ -32,8 +43,9 @@ pub enum RegisterPreflightError{
 }
 impl DriveService{
 	pub fn new(
-		config:Arc<MisskeyConfig>,
+		config:Reloadable<MisskeyConfig>,
 		db:DataBase,
+		redis:MultiplexedConnection,
 		meta_service:MetaService,
 		role_service:RoleService,
 		id_service:IdService,
@@ -43,6 +55,7 @@ impl DriveService{
 		Self{
 			config,
 			db,
+			redis,
 			meta_service,
 			role_service,
 			id_service,
@@ -81,14 +94,17 @@ impl DriveService{
 			}
 		}
 		//ファイル単位の容量制限チェック
+		let mut file_size_limit=None;
 		if let Some(user)=user.as_ref(){
 			if user.host.is_some() {
 				//remote user skip
 			} else {
 				let policies=self.role_service.get_user_policies(Some(user.id.as_str())).await;
-				if size > policies.file_size_limit.unwrap().saturating_mul(1024 * 1024) {
+				let limit=policies.file_size_limit.unwrap().saturating_mul(1024 * 1024);
+				if size > limit {
 					return Err(RegisterPreflightError::FileSizeLimitOver);
 				}
+				file_size_limit=Some(limit);
 			}
 		}else{
 			//system user skip
@@ -102,29 +118,19 @@ impl DriveService{
 				return Err(RegisterPreflightError::BadExt);
 			}
 		}
-		let detected_name = correct_filename(
-			// DriveFile.nameは256文字, validateFileNameは200文字制限であるため、
-			// extを付加してデータベースの文字数制限に当たることはまずない
-			if validate_file_name(name){
-				name
-			}else{
-				"untitled"
-			},
-			ext,
-		);
+		let detected_name = detect_name(name,ext);
 		//#region Check drive usage
 		let mut con=self.db.get().await.ok_or(RegisterPreflightError::InternalServerError)?;
+		let mut free_space=None;
 		if !is_link {
 			if let Some(user)=user.as_ref(){
-				let usage = calc_drive_usage_of(&mut con,&user.id).await;
-
-				let policies = self.role_service.get_user_policies(Some(user.id.as_str())).await;
-				let drive_capacity = 1024 * 1024 * policies.drive_capacity_mb.unwrap_or_default();
+				let free = self.free_space_with(&mut con,&user.id).await;
 
 				// If usage limit exceeded
-				if drive_capacity < usage + size {
+				if free < size {
 				return Err(RegisterPreflightError::NoFreeSpace);
 				}
+				free_space=Some(free);
 			}
 		}
 		//#endregion
@@ -144,8 +150,112 @@ impl DriveService{
 			sensitive_threshold,
 			enable_sensitive_media_detection_for_videos: instance.enable_sensitive_media_detection_for_videos,
 			detected_name,
+			free_space,
+			file_size_limit,
 		})
 	}
+	/**
+	 * ドライブの空き容量(バイト)
+	 * 進行中のアップロードの予約分は含まない
+	 */
+	pub async fn free_space(&self,user_id:&str)->Result<i64,RegisterPreflightError>{
+		let mut con=self.db.get().await.ok_or(RegisterPreflightError::InternalServerError)?;
+		Ok(self.free_space_with(&mut con,user_id).await)
+	}
+	async fn free_space_with(&self,con:&mut DBConnection<'_>,user_id:&str)->i64{
+		let usage = calc_drive_usage_of(con,user_id).await;
+		let policies = self.role_service.get_user_policies(Some(user_id)).await;
+		let drive_capacity = 1024 * 1024 * policies.drive_capacity_mb.unwrap_or_default();
+		drive_capacity - usage
+	}
+	/**
+	 * アップロード前にクライアントが申告したハッシュと同じファイルを探す
+	 * sha256はこのサービスで内容から計算して記録し、期限が切れていないものだけが見つかる
+	 */
+	pub async fn find_duplicate(&self,
+		user:&MiUser,
+		md5:Option<&str>,
+		sha256:Option<&str>,
+		sensitive:bool,
+	)->Option<(MiDriveFile,Option<serde_json::Value>)>{
+		let mut con=self.db.get().await?;
+		let mut matched=None;
+		if let Some(md5)=md5{
+			matched=find_by_md5(&mut con,&user.id,&md5.to_ascii_lowercase()).await;
+		}
+		if let (None,Some(sha256))=(matched.as_ref(),sha256){
+			use redis::AsyncCommands;
+			let mut redis=self.redis.clone();
+			let key=sha256_index_key(&user.id,&sha256.to_ascii_lowercase());
+			let file_id=redis.get::<&String,Option<String>>(&key).await.map_err(|e|{
+				tracing::error!("{:?}",e);
+			}).ok().flatten();
+			if let Some(file_id)=file_id{
+				use diesel::{ExpressionMethods, QueryDsl, SelectableHelper};
+				use diesel_async::RunQueryDsl;
+				use crate::models::drive_file::drive_file::dsl::drive_file;
+				use crate::models::drive_file::drive_file::dsl::*;
+				matched=drive_file.filter(userId.eq(user.id.as_str())).filter(id.eq(file_id.as_str())).select(MiDriveFile::as_select()).first(&mut con).await.ok();
+				if matched.is_none(){
+					//削除済みのファイルを指している
+					let _=redis.del::<&String,()>(&key).await;
+				}
+			}
+		}
+		let mut matched=matched?;
+		tracing::debug!("file with same hash is found: {}",matched.id);
+		mark_sensitive(&mut con,&mut matched,sensitive).await;
+		let packed_file=self.pack(&mut con,&matched,true,false,false,None,Some(user)).await;
+		Some((matched,packed_file))
+	}
+	/**
+	 * 内容から計算したsha256をfind_duplicateで探せるように記録する
+	 * Misskey側でファイルが削除されても分からないので、期限を付けて古いものは消えるようにする
+	 */
+	pub async fn record_sha256(&self,user_id:&str,sha256:&str,file_id:&str){
+		use redis::AsyncCommands;
+		let mut redis=self.redis.clone();
+		if let Err(e)=redis.set_ex::<String,&str,()>(sha256_index_key(user_id,sha256),file_id,SHA256_INDEX_TTL).await{
+			tracing::error!("{:?}",e);
+		}
+	}
+	/**
+	 * 登録より先にファイルのIDが必要な場合に使う
+	 */
+	pub fn gen_file_id(&self)->String{
+		self.id_service.gen(None)
+	}
+	/**
+	 * ユーザーのファイルをIDで探してpackしたものと返す
+	 */
+	pub async fn find_file(&self,user:&MiUser,file_id:&str)->Option<(MiDriveFile,Option<serde_json::Value>)>{
+		use diesel::{ExpressionMethods, QueryDsl, SelectableHelper};
+		use diesel_async::RunQueryDsl;
+		use crate::models::drive_file::drive_file::dsl::drive_file;
+		use crate::models::drive_file::drive_file::dsl::*;
+		let mut con=self.db.get().await?;
+		let file=drive_file.filter(userId.eq(user.id.as_str())).filter(id.eq(file_id)).select(MiDriveFile::as_select()).first(&mut con).await.ok()?;
+		let packed_file=self.pack(&mut con,&file,true,false,false,None,Some(user)).await;
+		Some((file,packed_file))
+	}
+	/**
+	 * ユーザーのファイルのコメントを変更してpackしたものと返す
+	 */
+	pub async fn update_comment(&self,user:&MiUser,file_id:&str,new_comment:Option<&str>)->Option<(MiDriveFile,Option<serde_json::Value>)>{
+		use diesel::{ExpressionMethods, QueryDsl};
+		use diesel_async::RunQueryDsl;
+		use crate::models::drive_file::drive_file::dsl::drive_file;
+		use crate::models::drive_file::drive_file::dsl::*;
+		let mut con=self.db.get().await?;
+		let updated=diesel::update(drive_file.filter(userId.eq(user.id.as_str())).filter(id.eq(file_id))).set(comment.eq(new_comment)).execute(&mut con).await.map_err(|e|{
+			tracing::error!("{:?}",e);
+		}).ok()?;
+		if updated==0{
+			return None;
+		}
+		drop(con);
+		self.find_file(user,file_id).await
+	}
 	pub async fn register_file(&self,
 		user:Option<&MiUser>,
 		access_key:&str,
@@ -167,6 +277,7 @@ impl DriveService{
 		force:bool,
 		thumbnail_key:Option<&str>,
 		base_url:String,
+		file_id:Option<String>,//gen_file_idで先に決めたID
 	)->Option<(MiDriveFile,Option<serde_json::Value>)>{
 		let mut con=self.db.get().await?;
 		let user_id=user.as_ref().map(|user|user.id.as_str());
@@ -188,39 +299,17 @@ impl DriveService{
 		}
 		if user_id.is_some() && !force {
 			// Check if there is a file with the same hash
-			use diesel::{ExpressionMethods, QueryDsl, SelectableHelper};
-			use diesel_async::RunQueryDsl;
-			let user_id=user_id.unwrap();
-			let target_hash=md5.as_str();
-			let matched :Option<MiDriveFile>={
-				use crate::models::drive_file::drive_file::dsl::drive_file;
-				use crate::models::drive_file::drive_file::dsl::*;
-				drive_file.filter(userId.eq(user_id)).filter(md5.eq(target_hash)).select(MiDriveFile::as_select()).first(&mut con).await.map_err(|e|{
-					eprintln!("{:?}",e);
-				})
-			}.ok();
-
+			let matched=find_by_md5(&mut con,user_id.unwrap(),md5.as_str()).await;
 			if let Some(mut matched)=matched {
-				println!("file with same hash is found: {}",matched.id);
-				if sensitive && !matched.is_sensitive {
-					// The file is federated as sensitive for this time, but was federated as non-sensitive before.
-					// Therefore, update the file to sensitive.
-					use crate::models::drive_file::drive_file::dsl::drive_file;
-					use crate::models::drive_file::drive_file::dsl::*;
-					let is_ok=diesel::update(drive_file.filter(id.eq(matched.id.as_str()))).set(isSensitive.eq(true)).execute(&mut con).await.map_err(|e|{
-						eprintln!("{:?}",e);
-					}).is_ok();
-					if is_ok{
-						matched.is_sensitive = true;
-					}
-				}
+				tracing::debug!("file with same hash is found: {}",matched.id);
+				mark_sensitive(&mut con,&mut matched,sensitive).await;
 				let packed_file=self.pack(&mut con,&matched,true,false,false,folder.as_ref(),user).await;
 				return Some((matched,packed_file));
 			}
 		}
 
 		let mut file = MiDriveFile{
-			id : self.id_service.gen(None),
+			id : file_id.unwrap_or_else(||self.id_service.gen(None)),
 			user_id : user.as_ref().map(|user|user.id.to_owned()),
 			user_host : user.as_ref().map(|user|user.host.to_owned()).unwrap_or_default(),
 			folder_id : folder.as_ref().map(|folder|folder.id.to_owned()),
@@ -285,12 +374,12 @@ impl DriveService{
 				diesel::insert_into(drive_file).values(&file).execute(&mut con).await
 			};
 			if let Err(e)=db_res{
-				eprintln!("drive File Insert Error {:?}",e);
+				tracing::error!("drive File Insert Error {:?}",e);
 				file={
 					use crate::models::drive_file::drive_file::dsl::drive_file;
 					use crate::models::drive_file::drive_file::dsl::*;
 					drive_file.filter(userId.eq(user_id)).filter(url.eq(file.url.as_str())).select(MiDriveFile::as_select()).first(&mut con).await.map_err(|e|{
-						eprintln!("{:?}",e);
+						tracing::error!("{:?}",e);
 					})
 				}.ok()?;
 			}
@@ -312,12 +401,12 @@ impl DriveService{
 			let db_res={
 				use crate::models::drive_file::drive_file::dsl::drive_file;
 				diesel::insert_into(drive_file).values(&file).execute(&mut con).await.map_err(|e|{
-					eprintln!("{:?}",e);
+					tracing::error!("{:?}",e);
 					e
 				})
 			};
 		}
-		println!("drive file has been created {}",file.id);
+		tracing::info!("drive file has been created {}",file.id);
 
 		let packed_file=self.pack(&mut con,&file,true,false,false,folder.as_ref(),user).await;
 		if let Some(user)=user.as_ref() {
@@ -464,7 +553,7 @@ impl DriveService{
 				use crate::models::drive_folder::drive_folder::dsl::drive_folder;
 				use crate::models::drive_folder::drive_folder::dsl::*;
 				drive_folder.filter(parentId.eq(folder.id.as_str())).count().get_result(con).await.map_err(|e|{
-					eprintln!("{:?}",e);
+					tracing::error!("{:?}",e);
 				})
 			}.ok()?;
 			map.insert("foldersCount".into(),folders_count.into());
@@ -472,7 +561,7 @@ impl DriveService{
 				use crate::models::drive_file::drive_file::dsl::drive_file;
 				use crate::models::drive_file::drive_file::dsl::*;
 				drive_file.filter(folderId.eq(folder.id.as_str())).count().get_result(con).await.map_err(|e|{
-					eprintln!("{:?}",e);
+					tracing::error!("{:?}",e);
 				})
 			}.ok()?;
 			map.insert("filesCount".into(),files_count.into());
@@ -501,12 +590,12 @@ impl DriveService{
 	
 			//return this.videoProcessingService.getExternalVideoThumbnailUrl(file.webpublicUrl ?? file.url);
 			return None;
-		} else if file.uri.is_some() && file.user_host.is_some() && self.config.media_proxy.is_some() {
+		} else if file.uri.is_some() && file.user_host.is_some() && self.config.load().media_proxy.is_some() {
 			// 動画ではなくリモートかつメディアプロキシ
 			return Some(self.get_proxied_url(file.uri.as_ref().unwrap().as_str(), Some("static")));
 		}
 	
-		if file.uri.is_some() && file.is_link && self.config.proxy_remote_files.unwrap_or(false) {
+		if file.uri.is_some() && file.is_link && self.config.load().proxy_remote_files.unwrap_or(false) {
 			// リモートかつ期限切れはローカルプロキシを試みる
 			// 従来は/files/${thumbnailAccessKey}にアクセスしていたが、
 			// /filesはメディアプロキシにリダイレクトするようにしたため直接メディアプロキシを指定する
@@ -535,7 +624,7 @@ impl DriveService{
 		}
 	}
 	fn get_proxied_url(&self,url: &str, mode: Option<&str>)-> String {
-		if let Some(media_proxy)=self.config.media_proxy.as_ref(){
+		if let Some(media_proxy)=self.config.load().media_proxy.as_ref(){
 			let mut s=format!("{}/{}.webp?url={}",media_proxy,mode.unwrap_or("image"),url);
 			if let Some(mode)=mode{
 				s+="&";
@@ -550,26 +639,26 @@ impl DriveService{
 	fn get_public_url(&self,file: &MiDriveFile, mode: Option<&str>, ap: bool)-> String { // static = thumbnail
 		// PublicUrlにはexternalMediaProxyEnabledでもremoteProxyを使う
 		// https://github.com/yojo-art/cherrypick/issues/84
-		if file.uri.is_some() && file.user_host.is_some() && mode.is_none() && self.config.remote_proxy.is_some() {
+		if file.uri.is_some() && file.user_host.is_some() && mode.is_none() && self.config.load().remote_proxy.is_some() {
 			let key = file.webpublic_access_key.as_ref();
 			if key.is_some() && key.unwrap().find('/').is_none() {	// 古いものはここにオブジェクトストレージキーが入ってるので除外
-				if self.config.remote_proxy.as_ref().unwrap().starts_with("/") {
-					return format!("{}{}/{}",self.config.url,self.config.remote_proxy.as_ref().unwrap(),key.unwrap());
+				if self.config.load().remote_proxy.as_ref().unwrap().starts_with("/") {
+					return format!("{}{}/{}",self.config.load().url,self.config.load().remote_proxy.as_ref().unwrap(),key.unwrap());
 				}
-				return format!("{}/{}",self.config.remote_proxy.as_ref().unwrap(),key.unwrap());
+				return format!("{}/{}",self.config.load().remote_proxy.as_ref().unwrap(),key.unwrap());
 			}
 		}
 		// リモートかつメディアプロキシ
-		if file.uri.is_some() && file.user_host.is_some() && self.config.media_proxy.is_some() {
+		if file.uri.is_some() && file.user_host.is_some() && self.config.load().media_proxy.is_some() {
 			return self.get_proxied_url(file.uri.as_ref().unwrap(), mode);
 		}
 	
 		// リモートかつ期限切れはローカルプロキシを試みる
-		if file.uri.is_some() && file.is_link && self.config.proxy_remote_files.unwrap_or(false){
+		if file.uri.is_some() && file.is_link && self.config.load().proxy_remote_files.unwrap_or(false){
 			let key = file.webpublic_access_key.as_ref();
 	
 			if key.is_some() && key.unwrap().find('/').is_none() {	// 古いものはここにオブジェクトストレージキーが入ってるので除外
-				let url = format!("{}/files/{}",self.config.url,key.unwrap());
+				let url = format!("{}/files/{}",self.config.load().url,key.unwrap());
 				if mode == Some("avatar"){
 					return self.get_proxied_url(file.uri.as_ref().unwrap(), Some("avatar"));
 				}
@@ -583,8 +672,9 @@ impl DriveService{
 			return self.get_proxied_url(file.uri.as_ref().unwrap(), Some("avatar"));
 		}
 	
-		if ap && self.config.ap_file_base_url.is_some() {
-			let ap_file_base_url = self.config.ap_file_base_url.as_ref().unwrap();
+		let config=self.config.load();
+		if ap && config.ap_file_base_url.is_some() {
+			let ap_file_base_url = config.ap_file_base_url.as_ref().unwrap();
 			match(reqwest::Url::from_str(ap_file_base_url.as_str()),reqwest::Url::from_str(url.as_str())){
 				(Ok(ap_file_base_url),Ok(mut url))=>{
 					let host=url.set_host(ap_file_base_url.host_str());
@@ -628,7 +718,7 @@ pub async fn fetch_folder(con:&mut DBConnection<'_>,folder_id: Option<&str>,user
 		use crate::models::drive_folder::drive_folder::dsl::drive_folder;
 		use crate::models::drive_folder::drive_folder::dsl::*;
 		drive_folder.filter(userId.eq(user_id)).filter(id.eq(folder_id)).select(MiDriveFolder::as_select()).first(con).await.map_err(|e|{
-			eprintln!("{:?}",e);
+			tracing::error!("{:?}",e);
 		})
 	}.ok()?;
 	Some(res)
@@ -642,14 +732,58 @@ pub async fn calc_drive_usage_of(con:&mut DBConnection<'_>,user_id: &str)-> i64
 	use diesel_async::RunQueryDsl;
 	//64bit拡張の値はすべて読む
 	let size_long_sum:Option<i64>=drive_file.filter(userId.eq(user_id)).filter(isLink.eq(false)).select(sum(size_long)).first::<Option<bigdecimal::BigDecimal>>(con).await.map_err(|e|{
-		eprintln!("{:?}",e);
+		tracing::error!("{:?}",e);
 	}).ok().unwrap_or_default().map(|a|a.to_i64()).unwrap_or_default();
 	//32bit基本の値は64bit値が0の物のみ
 	let size_sum:Option<i64>=drive_file.filter(userId.eq(user_id)).filter(isLink.eq(false)).filter(size_long.eq(0)).select(sum(size)).first::<Option<i64>>(con).await.map_err(|e|{
-		eprintln!("{:?}",e);
+		tracing::error!("{:?}",e);
 	}).ok().unwrap_or_default();
 	size_long_sum.unwrap_or(0)+size_sum.unwrap_or(0)
 }
+async fn find_by_md5(con:&mut DBConnection<'_>,user_id:&str,target_hash:&str)->Option<MiDriveFile>{
+	use diesel::{ExpressionMethods, OptionalExtension, QueryDsl, SelectableHelper};
+	use diesel_async::RunQueryDsl;
+	use crate::models::drive_file::drive_file::dsl::drive_file;
+	use crate::models::drive_file::drive_file::dsl::*;
+	drive_file.filter(userId.eq(user_id)).filter(md5.eq(target_hash)).select(MiDriveFile::as_select()).first(con).await.optional().map_err(|e|{
+		tracing::error!("{:?}",e);
+	}).ok().flatten()
+}
+async fn mark_sensitive(con:&mut DBConnection<'_>,matched:&mut MiDriveFile,sensitive:bool){
+	if sensitive && !matched.is_sensitive {
+		// The file is federated as sensitive for this time, but was federated as non-sensitive before.
+		// Therefore, update the file to sensitive.
+		use diesel::{ExpressionMethods, QueryDsl};
+		use diesel_async::RunQueryDsl;
+		use crate::models::drive_file::drive_file::dsl::drive_file;
+		use crate::models::drive_file::drive_file::dsl::*;
+		let is_ok=diesel::update(drive_file.filter(id.eq(matched.id.as_str()))).set(isSensitive.eq(true)).execute(con).await.map_err(|e|{
+			tracing::error!("{:?}",e);
+		}).is_ok();
+		if is_ok{
+			matched.is_sensitive = true;
+		}
+	}
+}
+fn sha256_index_key(user_id:&str,sha256:&str)->String{
+	format!("driveFileSha256:{}:{}",user_id,sha256)
+}
+/**
+ * 保存するファイル名を決める
+ * extはregister_preflightで検査済みのもの
+ */
+pub fn detect_name(name:&str,ext:Option<&str>)->String{
+	correct_filename(
+		// DriveFile.nameは256文字, validateFileNameは200文字制限であるため、
+		// extを付加してデータベースの文字数制限に当たることはまずない
+		if validate_file_name(name){
+			name
+		}else{
+			"untitled"
+		},
+		ext,
+	)
+}
 fn validate_file_name(name: &str)-> bool {
 	return 
 		(name.trim().len() > 0) &&
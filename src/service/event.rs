@@ -1,16 +1,18 @@
 use std::sync::Arc;
 
+use futures::StreamExt;
 use redis::{aio::MultiplexedConnection, AsyncCommands};
 use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
 
-use crate::MisskeyConfig;
+use crate::{config::Reloadable, MisskeyConfig};
 
 pub enum StreamChannels<'a>{
 	Main(&'a String),
 	Drive(&'a String),
 }
 impl StreamChannels<'_>{
-	fn channel_id(&self)->String{
+	pub fn channel_id(&self)->String{
 		match self{
 			StreamChannels::Main(user_id) => format!("mainStream:{}",user_id.as_str()),
 			StreamChannels::Drive(user_id) => format!("driveStream:{}",user_id.as_str()),
@@ -25,12 +27,28 @@ pub enum MainEventType{
 #[derive(Clone,Serialize,Deserialize,Debug)]
 pub enum DriveEventType{
 	#[serde(rename = "fileCreated")]
-	FileCreated
+	FileCreated,
+	//分割アップロードのパートがS3に保存された
+	#[serde(rename = "uploadProgress")]
+	UploadProgress,
+	//finish-upload後のサーバー側の処理段階
+	#[serde(rename = "fileProcessing")]
+	FileProcessing,
+}
+/**
+ * Redisから受信したストリームイベント
+ * channelはmainStream:{userId}の形式
+ */
+#[derive(Clone,Debug)]
+pub struct StreamEvent{
+	pub channel:String,
+	pub message:serde_json::Value,
 }
 #[derive(Clone,Debug)]
 pub struct EventService{
 	redis:MultiplexedConnection,
-	config:Arc<MisskeyConfig>,
+	config:Reloadable<MisskeyConfig>,
+	local:broadcast::Sender<Arc<StreamEvent>>,
 }
 #[derive(Debug)]
 pub enum EventError{
@@ -49,11 +67,69 @@ impl From<redis::RedisError> for EventError{
 	}
 }
 impl EventService{
-	pub fn new(redis:MultiplexedConnection,config:Arc<MisskeyConfig>,)->Self{
+	pub fn new(redis:MultiplexedConnection,config:Reloadable<MisskeyConfig>,)->Self{
+		let (local,_)=broadcast::channel(1024);
 		Self{
-			redis,config
+			redis,config,local
 		}
 	}
+	fn pubsub_channel(&self)->Result<String,EventError>{
+		let host=reqwest::Url::parse(&self.config.load().url).map_err(|e|EventError::ConfigUrl(e.to_string()))?;
+		let host=host.host_str().ok_or_else(||EventError::ConfigUrl("NoHost".to_owned()))?;
+		Ok(host.to_owned())
+	}
+	/**
+	 * Redisのpubsubを購読してsubscribeした受信者に配信する
+	 * 購読には専用の接続が必要なのでClientを受け取る
+	 */
+	pub fn spawn_subscriber(&self,client:redis::Client){
+		let local=self.local.clone();
+		let channel=match self.pubsub_channel(){
+			Ok(channel)=>channel,
+			Err(e)=>{
+				tracing::error!("{:?}",e);
+				return;
+			}
+		};
+		tokio::spawn(async move{
+			loop{
+				match client.get_async_pubsub().await{
+					Ok(mut pubsub)=>{
+						if let Err(e)=pubsub.subscribe(&channel).await{
+							tracing::error!("{:?}",e);
+						}else{
+							let mut stream=pubsub.on_message();
+							while let Some(msg)=stream.next().await{
+								let payload=match msg.get_payload::<String>(){
+									Ok(payload)=>payload,
+									Err(_)=>continue,
+								};
+								let event=match serde_json::from_str::<serde_json::Value>(&payload){
+									Ok(serde_json::Value::Object(mut map))=>{
+										match (map.remove("channel"),map.remove("message")){
+											(Some(serde_json::Value::String(channel)),Some(message))=>StreamEvent{channel,message},
+											_=>continue,
+										}
+									},
+									_=>continue,
+								};
+								//受信者が居ない時のエラーは無視する
+								let _=local.send(Arc::new(event));
+							}
+						}
+					},
+					Err(e)=>{
+						tracing::error!("{:?}",e);
+					}
+				}
+				//切断されたら少し待って再接続する
+				tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+			}
+		});
+	}
+	pub fn subscribe(&self)->broadcast::Receiver<Arc<StreamEvent>>{
+		self.local.subscribe()
+	}
 	async fn publish(&self,channel: StreamChannels<'_>, t: Option<serde_json::Value>, value: Option<serde_json::Value>)->Result<(),EventError>{
 		let message=match (t,value){
 			(None, None) => serde_json::Value::Null,
@@ -70,10 +146,9 @@ impl EventService{
 		map.insert("message".to_string(),message.into());
 		let res=serde_json::to_string(&map)?;
 		let mut r=self.redis.clone();
-		let host=reqwest::Url::parse(&self.config.url).map_err(|e|EventError::ConfigUrl(e.to_string()))?;
-		let host=host.host_str().ok_or_else(||EventError::ConfigUrl("NoHost".to_owned()))?;
-		println!("publish event {}",host);
-		Ok(r.publish::<&str,String,()>(host,res).await?)
+		let host=self.pubsub_channel()?;
+		tracing::debug!("publish event {}",host);
+		Ok(r.publish::<&str,String,()>(&host,res).await?)
 	}
 	pub async fn publish_main_stream(&self,user_id:&String,event_type: Option<MainEventType>, value: Option<serde_json::Value>)->Result<(),EventError>{
 		let event_type=match event_type{
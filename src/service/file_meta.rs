@@ -5,9 +5,15 @@ use tokio::io::AsyncReadExt;
 
 use crate::ConfigFile;
 
+use super::metrics::MetricsService;
+
+//アップロード時に作るサムネイルの長辺の最大サイズ
+pub const THUMBNAIL_SIZE:u32=2048;
+
 #[derive(Clone,Debug)]
 pub struct FileMetaService{
 	model:Arc<nsfw::Model>,
+	metrics:MetricsService,
 }
 #[derive(Default,Clone,Debug)]
 pub struct FileMetaData{
@@ -18,11 +24,12 @@ pub struct FileMetaData{
 	pub thumbnail: Option<Vec<u8>>,
 }
 impl FileMetaService{
-	pub(crate) fn new()->Self{
+	pub(crate) fn new(metrics:MetricsService)->Self{
 		let model = nsfw::create_model(std::io::Cursor::new(include_bytes!("../../assets/model.onnx")));
 		let model=Arc::new(model.unwrap());
 		Self{
 			model,
+			metrics,
 		}
 	}
 	pub async fn metadata(&self,img:DynamicImage,sensitive_threshold:f32,skip_sensitive_detection:bool,thumbnail_size:u32,thumbnail_quality:f32,filter:fast_image_resize::FilterType)->FileMetaData{
@@ -41,7 +48,9 @@ impl FileMetaService{
 		let maybe_sensitive=if skip_sensitive_detection{
 			None
 		}else{
+			let nsfw_duration=self.metrics.nsfw_duration.clone();
 			Some(tokio::task::spawn_blocking(move||{
+				let _timer=nsfw_duration.start_timer();
 				match examine(&model,detection_src){
 					Ok(res)=>{
 						let mut sensitive=false;
@@ -103,8 +112,11 @@ impl FileMetaService{
 		if let Ok(mut process)=tokio::process::Command::new(ffmpeg).stdout(std::process::Stdio::piped()).args(["-loglevel","quiet","-i",url.as_str(),"-frames:v","1","-f","image2pipe","-"]).spawn(){
 			if let Some(mut stdout)=process.stdout.take(){
 				let mut img=vec![];
-				if let Err(e)=stdout.read_to_end(&mut img).await{
-					println!("{:?}",e);
+				let timer=self.metrics.ffmpeg_duration.start_timer();
+				let res=stdout.read_to_end(&mut img).await;
+				timer.observe_duration();
+				if let Err(e)=res{
+					tracing::warn!("{:?}",e);
 				}else{
 					if let Ok(img)=image::load_from_memory(&img){
 						let info=self.metadata(img,sensitive_threshold,skip_sensitive_detection, thumbnail_size,config.thumbnail_quality,config.thumbnail_filter.into()).await;
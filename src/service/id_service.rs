@@ -17,7 +17,7 @@ trait IdServiceImpl:Debug+Send+Sync{
 	fn parse(&self,id: &str)->Option<i64>;
 }
 impl IdService{
-	pub fn new(config: &MisskeyConfig)->Self{
+	pub fn new(config: &MisskeyConfig)->Result<Self,String>{
 		let method=config.id.to_lowercase();
 		let inner:Box<dyn IdServiceImpl>=match method.as_ref() {
 			"aid"=> Box::new(aid::AidService::new()),
@@ -26,9 +26,9 @@ impl IdService{
 			"meidg"=> Box::new(meidg::MeidgService::new()),
 			"ulid"=>  Box::new(misskey_ulid::UlidService::new()),
 			"objectid"=> Box::new(object_id::ObjectIdService::new()),
-			_=>unimplemented!("IdService"),
+			_=>return Err(format!("id: unsupported method {:?}",config.id)),
 		};
-		Self(Arc::new((method,inner)))
+		Ok(Self(Arc::new((method,inner))))
 	}
 	pub fn is_safe_t(&self,t: i64)-> bool {
 		self.0.1.is_safe_t(t)
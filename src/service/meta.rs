@@ -31,9 +31,9 @@ impl MetaService{
 		}
 		let mut con=self.db.get().await?;
 		let res:MiMeta={
-			use crate::models::meta::meta::dsl::meta;
-			meta.select(MiMeta::as_select()).first(&mut con).await.map_err(|e|{
-				eprintln!("{:?}",e);
+			use crate::models::meta::meta::dsl::meta as meta_table;
+			meta_table.select(MiMeta::as_select()).first(&mut con).await.map_err(|e|{
+				tracing::error!("{:?}",e);
 			})
 		}.ok()?;
 		let v=Some(Arc::new(res));
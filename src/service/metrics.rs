@@ -0,0 +1,85 @@
+use std::sync::Arc;
+
+use prometheus::{Encoder, Histogram, HistogramOpts, HistogramVec, IntCounterVec, IntGauge, Opts, Registry, TextEncoder};
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub struct MetricsConfig{
+	//指定した場合は別のアドレスで/metricsを公開する、省略時は通常のポートで公開する
+	pub bind_addr:Option<String>,
+}
+#[derive(Debug)]
+pub struct Metrics{
+	registry:Registry,
+	pub upload_bytes:IntCounterVec,
+	pub upload_duration:HistogramVec,
+	pub multipart_sessions:IntCounterVec,
+	pub s3_errors:IntCounterVec,
+	pub ffmpeg_duration:Histogram,
+	pub nsfw_duration:Histogram,
+	pub proxy_responses:IntCounterVec,
+	pub websocket_connections:IntGauge,
+}
+#[derive(Debug)]
+pub struct GaugeGuard(IntGauge);
+impl Drop for GaugeGuard{
+	fn drop(&mut self) {
+		self.0.dec();
+	}
+}
+#[derive(Clone,Debug)]
+pub struct MetricsService(Arc<Metrics>);
+impl std::ops::Deref for MetricsService{
+	type Target=Metrics;
+	fn deref(&self) -> &Self::Target {
+		&self.0
+	}
+}
+impl MetricsService{
+	pub fn new()->Self{
+		let registry=Registry::new_custom(Some("upload_service".to_owned()),None).unwrap();
+		let upload_bytes=IntCounterVec::new(Opts::new("upload_bytes_total","Bytes received by upload endpoints"),&["endpoint"]).unwrap();
+		//大きなファイルのアップロードは数分かかる
+		let upload_duration=HistogramVec::new(HistogramOpts::new("upload_duration_seconds","Latency of upload endpoints").buckets(vec![0.05,0.1,0.25,0.5,1.0,2.5,5.0,10.0,30.0,60.0,180.0]),&["endpoint","status"]).unwrap();
+		let multipart_sessions=IntCounterVec::new(Opts::new("multipart_sessions_total","Multipart upload sessions by event"),&["event"]).unwrap();
+		let s3_errors=IntCounterVec::new(Opts::new("s3_errors_total","Failed S3 operations"),&["operation"]).unwrap();
+		let ffmpeg_duration=Histogram::with_opts(HistogramOpts::new("ffmpeg_duration_seconds","Duration of ffmpeg thumbnail extraction").buckets(vec![0.1,0.25,0.5,1.0,2.5,5.0,10.0,30.0])).unwrap();
+		let nsfw_duration=Histogram::with_opts(HistogramOpts::new("nsfw_duration_seconds","Duration of NSFW detection").buckets(vec![0.01,0.025,0.05,0.1,0.25,0.5,1.0,2.5])).unwrap();
+		let proxy_responses=IntCounterVec::new(Opts::new("proxy_responses_total","Responses from upstream backends"),&["backend","status"]).unwrap();
+		let websocket_connections=IntGauge::new("websocket_connections","Open proxied WebSocket connections").unwrap();
+		registry.register(Box::new(upload_bytes.clone())).unwrap();
+		registry.register(Box::new(upload_duration.clone())).unwrap();
+		registry.register(Box::new(multipart_sessions.clone())).unwrap();
+		registry.register(Box::new(s3_errors.clone())).unwrap();
+		registry.register(Box::new(ffmpeg_duration.clone())).unwrap();
+		registry.register(Box::new(nsfw_duration.clone())).unwrap();
+		registry.register(Box::new(proxy_responses.clone())).unwrap();
+		registry.register(Box::new(websocket_connections.clone())).unwrap();
+		Self(Arc::new(Metrics{
+			registry,
+			upload_bytes,
+			upload_duration,
+			multipart_sessions,
+			s3_errors,
+			ffmpeg_duration,
+			nsfw_duration,
+			proxy_responses,
+			websocket_connections,
+		}))
+	}
+	/**
+	 * 接続中のWebSocketとして数え、guardがdropされたら減らす
+	 */
+	pub fn websocket_connection(&self)->GaugeGuard{
+		self.websocket_connections.inc();
+		GaugeGuard(self.websocket_connections.clone())
+	}
+	pub fn s3_error(&self,operation:&str){
+		self.s3_errors.with_label_values(&[operation]).inc();
+	}
+	pub fn encode(&self)->Result<Vec<u8>,prometheus::Error>{
+		let mut buf=vec![];
+		TextEncoder::new().encode(&self.registry.gather(),&mut buf)?;
+		Ok(buf)
+	}
+}
@@ -0,0 +1,103 @@
+use redis::aio::MultiplexedConnection;
+
+//戻り値は予約できたら1
+const RESERVE_SCRIPT:&str=r#"
+local time=redis.call('TIME')
+local now=tonumber(time[1])*1000+math.floor(tonumber(time[2])/1000)
+local size=tonumber(ARGV[2])
+local free=tonumber(ARGV[3])
+local ttl=tonumber(ARGV[4])
+local entries=redis.call('HGETALL',KEYS[1])
+local reserved=0
+for i=1,#entries,2 do
+	local s,e=string.match(entries[i+1],'^(%d+):(%d+)$')
+	if s==nil or tonumber(e)<=now then
+		redis.call('HDEL',KEYS[1],entries[i])
+	elseif entries[i]~=ARGV[1] then
+		reserved=reserved+tonumber(s)
+	end
+end
+if free>=0 and reserved+size>free then
+	return 0
+end
+redis.call('HSET',KEYS[1],ARGV[1],string.format('%d:%d',size,now+ttl))
+if redis.call('PTTL',KEYS[1])<ttl then
+	redis.call('PEXPIRE',KEYS[1],ttl)
+end
+return 1
+"#;
+/**
+ * 分割アップロード中のセッションが使う予定のドライブ容量をユーザー毎に予約する
+ * driveReservation:{ユーザーID}のハッシュに{セッションID}=>"{バイト数}:{期限(ミリ秒)}"で保存する
+ * 期限切れの項目は予約時に削除する
+ */
+#[derive(Clone,Debug)]
+pub struct QuotaService{
+	redis:MultiplexedConnection,
+	script:redis::Script,
+}
+impl QuotaService{
+	pub fn new(redis:MultiplexedConnection)->Self{
+		Self{
+			redis,
+			script:redis::Script::new(RESERVE_SCRIPT),
+		}
+	}
+	/**
+	 * セッションの予約をsizeバイトに更新して期限をttl秒後に延長する
+	 * free_spaceは予約を含まない空き容量で、他のセッションの予約と合わせて超える場合はfalse
+	 * free_spaceがNoneの場合は容量を確認しない
+	 */
+	pub async fn reserve(&self,user_id:&str,session_id:&str,size:u64,free_space:Option<i64>,ttl:u64)->Result<bool,redis::RedisError>{
+		let mut redis=self.redis.clone();
+		let res:i64=self.script.key(reservation_key(user_id))
+			.arg(session_id)
+			.arg(size)
+			.arg(free_space.map(|v|v.max(0)).unwrap_or(-1))
+			.arg(ttl.saturating_mul(1000))
+			.invoke_async(&mut redis).await?;
+		Ok(res==1)
+	}
+	/**
+	 * セッションの予約を解放する
+	 */
+	pub async fn release(&self,user_id:&str,session_id:&str){
+		use redis::AsyncCommands;
+		let mut redis=self.redis.clone();
+		if let Err(e)=redis.hdel::<String,&str,()>(reservation_key(user_id),session_id).await{
+			tracing::error!("{:?}",e);
+		}
+	}
+	/**
+	 * dropされた時に予約を解放するguardを返す
+	 */
+	pub fn guard(&self,user_id:&str,session_id:&str)->ReservationGuard{
+		ReservationGuard{
+			quota:self.clone(),
+			user_id:user_id.to_owned(),
+			session_id:session_id.to_owned(),
+		}
+	}
+}
+/**
+ * リクエストの処理が中断された場合にも予約を残さないために、dropでは解放するタスクを起動する
+ */
+#[derive(Debug)]
+pub struct ReservationGuard{
+	quota:QuotaService,
+	user_id:String,
+	session_id:String,
+}
+impl Drop for ReservationGuard{
+	fn drop(&mut self) {
+		let quota=self.quota.clone();
+		let user_id=std::mem::take(&mut self.user_id);
+		let session_id=std::mem::take(&mut self.session_id);
+		tokio::spawn(async move{
+			quota.release(&user_id,&session_id).await;
+		});
+	}
+}
+fn reservation_key(user_id:&str)->String{
+	format!("driveReservation:{}",user_id)
+}
@@ -0,0 +1,115 @@
+use redis::{aio::MultiplexedConnection, AsyncCommands};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+//トークンから判別したユーザーを覚えておく秒数
+const USER_CACHE_TTL:u64=60;
+
+/**
+ * 設定ファイルのレート制限の1項目
+ * Misskeyと同じくduration(ミリ秒)の間にmax回までを上限とし、
+ * duration/max毎に1回分回復するトークンバケットとして扱う
+ */
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub struct RateLimitRule{
+	pub path:String,
+	pub methods:Option<Vec<String>>,
+	pub max:u32,
+	pub duration:u64,
+}
+impl RateLimitRule{
+	pub fn validate(&self,errors:&mut Vec<String>){
+		if self.max==0{
+			errors.push(format!("rate_limits[{}].max: must be greater than 0",self.path));
+		}
+		if self.duration==0{
+			errors.push(format!("rate_limits[{}].duration: must be greater than 0",self.path));
+		}
+	}
+}
+//戻り値は{許可なら1,再試行までのミリ秒}
+const TOKEN_BUCKET_SCRIPT:&str=r#"
+local capacity=tonumber(ARGV[1])
+local refill=tonumber(ARGV[2])
+local time=redis.call('TIME')
+local now=tonumber(time[1])*1000+math.floor(tonumber(time[2])/1000)
+local data=redis.call('HMGET',KEYS[1],'tokens','ts')
+local tokens=tonumber(data[1]) or capacity
+local ts=tonumber(data[2]) or now
+tokens=math.min(capacity,tokens+math.max(0,now-ts)/refill)
+local allowed=0
+local retry=0
+if tokens>=1 then
+	tokens=tokens-1
+	allowed=1
+else
+	retry=math.ceil((1-tokens)*refill)
+end
+redis.call('HSET',KEYS[1],'tokens',tostring(tokens),'ts',now)
+redis.call('PEXPIRE',KEYS[1],math.ceil(capacity*refill)+1000)
+return {allowed,retry}
+"#;
+#[derive(Clone,Debug)]
+pub struct RateLimitService{
+	redis:MultiplexedConnection,
+	script:redis::Script,
+}
+impl RateLimitService{
+	pub fn new(redis:MultiplexedConnection)->Self{
+		Self{
+			redis,
+			script:redis::Script::new(TOKEN_BUCKET_SCRIPT),
+		}
+	}
+	/**
+	 * 1回分を消費する
+	 * 制限を超えた場合は再試行までのミリ秒を返す
+	 * factorはロールのrateLimitFactorで、回復にかかる時間に掛ける
+	 */
+	pub async fn consume(&self,key:&str,rule:&RateLimitRule,factor:f64)->Result<(),u64>{
+		if rule.max==0||rule.duration==0{
+			return Ok(());
+		}
+		let refill=rule.duration as f64/rule.max as f64*factor;
+		let mut redis=self.redis.clone();
+		let res:Result<(i64,i64),_>=self.script.key(format!("rateLimit:{}",key)).arg(rule.max).arg(refill).invoke_async(&mut redis).await;
+		match res{
+			Ok((1,_))=>Ok(()),
+			Ok((_,retry))=>Err(retry.max(1) as u64),
+			Err(e)=>{
+				//Redisが使えない場合は制限しない
+				tracing::error!("{:?}",e);
+				Ok(())
+			}
+		}
+	}
+	/**
+	 * cache_userで覚えたトークンの(ユーザーID,factor)
+	 */
+	pub async fn cached_user(&self,token:&str)->Option<(String,f64)>{
+		let mut redis=self.redis.clone();
+		let value=match redis.get::<String,Option<String>>(user_cache_key(token)).await{
+			Ok(value)=>value?,
+			Err(e)=>{
+				tracing::error!("{:?}",e);
+				return None;
+			}
+		};
+		let (factor,user_id)=value.split_once(':')?;
+		Some((user_id.to_owned(),factor.parse().ok()?))
+	}
+	/**
+	 * 認証できたトークンのユーザーを短時間覚えて、毎回のDBへの問い合わせを省く
+	 */
+	pub async fn cache_user(&self,token:&str,user_id:&str,factor:f64){
+		let mut redis=self.redis.clone();
+		if let Err(e)=redis.set_ex::<String,String,()>(user_cache_key(token),format!("{}:{}",factor,user_id),USER_CACHE_TTL).await{
+			tracing::error!("{:?}",e);
+		}
+	}
+}
+//トークンそのものは保存しない
+fn user_cache_key(token:&str)->String{
+	let hash=Sha256::digest(token.as_bytes());
+	format!("rateLimitUser:{}",hash.iter().map(|n| format!("{:02x}", n)).collect::<String>())
+}
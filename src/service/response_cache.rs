@@ -0,0 +1,283 @@
+use std::{collections::HashMap, sync::Arc, time::{Duration, Instant}};
+
+use axum::{http::{HeaderMap, StatusCode}, response::IntoResponse};
+use redis::{aio::MultiplexedConnection, AsyncCommands};
+use serde::{Deserialize, Serialize};
+use tokio::sync::RwLock;
+
+//キャッシュのキーに含めるリクエストヘッダ、Varyがこれ以外を指定した応答は保存しない
+const KEYED_HEADERS:[&str;2]=["accept","accept-language"];
+
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub struct ResponseCacheConfig{
+	store:CacheStore,
+	//キャッシュしてよいパス("*"は任意の文字列)
+	paths:Vec<String>,
+	//Cache-Controlでmax-ageが指定されていない場合の秒数、0ならキャッシュしない
+	default_ttl:u64,
+	max_ttl:u64,
+	max_body_size:u64,
+	//storeがMemoryの場合の最大件数
+	max_entries:usize,
+	//設定した場合のみ/_cache/purgeを受け付ける
+	purge_token:Option<String>,
+}
+impl ResponseCacheConfig{
+	pub fn validate(&self,errors:&mut Vec<String>){
+		if self.max_ttl<self.default_ttl{
+			errors.push("response_cache.max_ttl: must not be less than default_ttl".to_owned());
+		}
+		if self.store==CacheStore::Memory&&self.max_entries==0{
+			errors.push("response_cache.max_entries: must be greater than 0".to_owned());
+		}
+	}
+}
+#[derive(Clone, Copy,Debug,PartialEq,Serialize,Deserialize)]
+pub enum CacheStore{
+	Memory,
+	Redis,
+}
+#[derive(Clone,Debug,Serialize,Deserialize)]
+struct CachedMeta{
+	status:u16,
+	headers:Vec<(String,String)>,
+	stored_at:i64,
+}
+#[derive(Clone,Debug)]
+pub struct CachedResponse{
+	meta:CachedMeta,
+	body:Vec<u8>,
+}
+impl CachedResponse{
+	pub fn new(status:u16,headers:&HeaderMap,body:Vec<u8>)->Self{
+		let headers=headers.iter().filter_map(|(k,v)|v.to_str().ok().map(|v|(k.to_string(),v.to_owned()))).collect();
+		Self{
+			meta:CachedMeta{
+				status,
+				headers,
+				stored_at:chrono::Utc::now().timestamp(),
+			},
+			body,
+		}
+	}
+	/**
+	 * Redisにはメタデータのjsonと本文を改行で区切って保存する
+	 */
+	fn to_bytes(&self)->Option<Vec<u8>>{
+		let mut buf=serde_json::to_vec(&self.meta).ok()?;
+		buf.push(b'\n');
+		buf.extend_from_slice(&self.body);
+		Some(buf)
+	}
+	fn from_bytes(buf:&[u8])->Option<Self>{
+		let idx=buf.iter().position(|b|*b==b'\n')?;
+		let meta=serde_json::from_slice(&buf[..idx]).ok()?;
+		Some(Self{
+			meta,
+			body:buf[idx+1..].to_vec(),
+		})
+	}
+	pub fn into_response(self,cache_status:&'static str)->axum::response::Response{
+		let mut header=HeaderMap::new();
+		for (k,v) in &self.meta.headers{
+			if let (Ok(k),Ok(v))=(axum::http::HeaderName::from_bytes(k.as_bytes()),axum::http::HeaderValue::from_str(v)){
+				header.append(k,v);
+			}
+		}
+		let age=(chrono::Utc::now().timestamp()-self.meta.stored_at).max(0);
+		header.insert(axum::http::header::AGE,age.into());
+		header.insert("x-cache",cache_status.parse().unwrap());
+		let status=StatusCode::from_u16(self.meta.status).unwrap_or(StatusCode::OK);
+		(status,header,self.body).into_response()
+	}
+}
+#[derive(Debug)]
+struct MemoryEntry{
+	expires:Instant,
+	response:CachedResponse,
+}
+#[derive(Clone,Debug)]
+pub struct ResponseCacheService{
+	config:Option<Arc<ResponseCacheConfig>>,
+	redis:MultiplexedConnection,
+	memory:Arc<RwLock<HashMap<String,MemoryEntry>>>,
+}
+impl ResponseCacheService{
+	pub fn new(config:Option<ResponseCacheConfig>,redis:MultiplexedConnection)->Self{
+		Self{
+			config:config.map(Arc::new),
+			redis,
+			memory:Arc::new(RwLock::new(HashMap::new())),
+		}
+	}
+	pub fn purge_token(&self)->Option<&str>{
+		self.config.as_ref()?.purge_token.as_deref()
+	}
+	/**
+	 * キャッシュ対象のリクエストならキーを返す
+	 * 匿名(Authorizationヘッダ,iパラメータ,tokenクッキーが無い)のGETに限る
+	 * KEYED_HEADERSの値は#以降に付ける
+	 */
+	pub fn cache_key(&self,method:&axum::http::Method,uri:&axum::http::Uri,headers:&HeaderMap)->Option<String>{
+		let config=self.config.as_ref()?;
+		if method!=axum::http::Method::GET{
+			return None;
+		}
+		if headers.contains_key(axum::http::header::AUTHORIZATION){
+			return None;
+		}
+		let query=uri.query().unwrap_or_default();
+		if query.split('&').any(|kv|kv=="i"||kv.starts_with("i=")){
+			return None;
+		}
+		let has_token_cookie=headers.get_all(axum::http::header::COOKIE).iter().filter_map(|v|v.to_str().ok()).flat_map(|v|v.split(';')).any(|kv|kv.trim().starts_with("token="));
+		if has_token_cookie{
+			return None;
+		}
+		let path=uri.path();
+		if !config.paths.iter().any(|p|crate::api::route_table::match_path(p,path)){
+			return None;
+		}
+		let vary=KEYED_HEADERS.iter().map(|name|{
+			headers.get_all(*name).iter().filter_map(|v|v.to_str().ok()).collect::<Vec<_>>().join(",")
+		}).collect::<Vec<_>>().join("|");
+		Some(match uri.query(){
+			Some(query)=>format!("responseCache:{}?{}#{}",path,query,vary),
+			None=>format!("responseCache:{}#{}",path,vary),
+		})
+	}
+	/**
+	 * バックエンドの応答を保存してよい期間
+	 */
+	pub fn ttl(&self,status:u16,headers:&HeaderMap)->Option<Duration>{
+		let config=self.config.as_ref()?;
+		if status!=200||headers.contains_key(axum::http::header::SET_COOKIE){
+			return None;
+		}
+		//Accept-Encodingはバックエンドへ送らないので圧縮されていない応答だけが届く
+		let unkeyed_vary=headers.get_all(axum::http::header::VARY).iter().filter_map(|v|v.to_str().ok()).flat_map(|v|v.split(',')).any(|v|{
+			let v=v.trim().to_lowercase();
+			!v.is_empty()&&v!="accept-encoding"&&!KEYED_HEADERS.contains(&v.as_str())
+		});
+		if unkeyed_vary{
+			return None;
+		}
+		let size=headers.get(axum::http::header::CONTENT_LENGTH).and_then(|v|v.to_str().ok()).and_then(|v|v.parse::<u64>().ok());
+		match size{
+			Some(size) if size<=config.max_body_size=>{},
+			_=>return None,
+		}
+		let mut ttl=None;
+		let mut shared_ttl=None;
+		for directive in headers.get_all(axum::http::header::CACHE_CONTROL).iter().filter_map(|v|v.to_str().ok()).flat_map(|v|v.split(',')){
+			let directive=directive.trim().to_lowercase();
+			match directive.split_once('='){
+				Some(("max-age",v))=>ttl=v.trim_matches('"').parse::<u64>().ok(),
+				Some(("s-maxage",v))=>shared_ttl=v.trim_matches('"').parse::<u64>().ok(),
+				None if directive=="no-store"||directive=="no-cache"||directive=="private"=>return None,
+				_=>{},
+			}
+		}
+		let ttl=shared_ttl.or(ttl).unwrap_or(config.default_ttl).min(config.max_ttl);
+		if ttl==0{
+			return None;
+		}
+		Some(Duration::from_secs(ttl))
+	}
+	pub async fn get(&self,key:&str)->Option<CachedResponse>{
+		let config=self.config.as_ref()?;
+		match config.store{
+			CacheStore::Memory=>{
+				let memory=self.memory.read().await;
+				let entry=memory.get(key)?;
+				if entry.expires<Instant::now(){
+					return None;
+				}
+				Some(entry.response.clone())
+			},
+			CacheStore::Redis=>{
+				let mut redis=self.redis.clone();
+				match redis.get::<&str,Option<Vec<u8>>>(key).await{
+					Ok(Some(buf))=>CachedResponse::from_bytes(&buf),
+					Ok(None)=>None,
+					Err(e)=>{
+						tracing::error!("{:?}",e);
+						None
+					}
+				}
+			},
+		}
+	}
+	pub async fn put(&self,key:String,response:CachedResponse,ttl:Duration){
+		let config=match self.config.as_ref(){
+			Some(config)=>config,
+			None=>return,
+		};
+		match config.store{
+			CacheStore::Memory=>{
+				let mut memory=self.memory.write().await;
+				if memory.len()>=config.max_entries{
+					let now=Instant::now();
+					memory.retain(|_,e|e.expires>=now);
+				}
+				if memory.len()>=config.max_entries&&!memory.contains_key(&key){
+					return;
+				}
+				memory.insert(key,MemoryEntry{
+					expires:Instant::now()+ttl,
+					response,
+				});
+			},
+			CacheStore::Redis=>{
+				let buf=match response.to_bytes(){
+					Some(buf)=>buf,
+					None=>return,
+				};
+				let mut redis=self.redis.clone();
+				if let Err(e)=redis.pset_ex::<String,Vec<u8>,()>(key,buf,ttl.as_millis() as u64).await{
+					tracing::error!("{:?}",e);
+				}
+			},
+		}
+	}
+	/**
+	 * pathsを省略した場合は全て削除する
+	 * パス毎の削除ではクエリ文字列違いのものも削除する
+	 */
+	pub async fn purge(&self,paths:Option<&[String]>)->Result<(),redis::RedisError>{
+		let config=match self.config.as_ref(){
+			Some(config)=>config,
+			None=>return Ok(()),
+		};
+		let matches=|key:&str|match paths{
+			Some(paths)=>paths.iter().any(|p|{
+				let key=key.split_once('#').map(|(k,_)|k).unwrap_or(key);
+				let prefix=format!("responseCache:{}",p);
+				key==prefix||key.starts_with(&format!("{}?",prefix))
+			}),
+			None=>true,
+		};
+		match config.store{
+			CacheStore::Memory=>{
+				self.memory.write().await.retain(|k,_|!matches(k));
+			},
+			CacheStore::Redis=>{
+				let mut redis=self.redis.clone();
+				let keys:Vec<String>={
+					let mut iter=redis.scan_match::<&str,String>("responseCache:*").await?;
+					let mut keys=vec![];
+					while let Some(key)=iter.next_item().await{
+						if matches(&key){
+							keys.push(key);
+						}
+					}
+					keys
+				};
+				for chunk in keys.chunks(100){
+					redis.del::<&[String],()>(chunk).await?;
+				}
+			},
+		}
+		Ok(())
+	}
+}
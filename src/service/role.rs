@@ -63,7 +63,7 @@ impl RoleService{
 			use crate::models::role::role_assignment::*;
 			use diesel_async::RunQueryDsl;
 			role_assignment.filter(userId.eq(user_id)).select(MiRoleAssignment::as_select()).load(&mut con).await.map_err(|e|{
-				eprintln!("{:?}",e);
+				tracing::error!("{:?}",e);
 			})
 		}.ok()?;
 		// 期限切れのロールを除外
@@ -87,7 +87,7 @@ impl RoleService{
 			use crate::models::role::role::dsl::role;
 			use diesel_async::RunQueryDsl;
 			role.select(MiRole::as_select()).load(&mut con).await.map_err(|e|{
-			eprintln!("{:?}",e);
+			tracing::error!("{:?}",e);
 			})
 		}.ok()?;
 		let assigns = self.get_user_assigns(user_id).await;
@@ -0,0 +1,249 @@
+use std::{hash::{DefaultHasher, Hash, Hasher}, sync::{atomic::{AtomicBool, AtomicI64, AtomicUsize, Ordering}, Arc}};
+
+use serde::{Deserialize, Serialize};
+
+use crate::ConfigFile;
+
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub struct UpstreamConfig{
+	backends:Vec<BackendConfig>,
+	balance:BalanceMode,
+	//空文字列ならアクティブヘルスチェックを行わない
+	health_check_path:String,
+	health_check_interval:u64,
+	health_check_timeout:u64,
+	//接続に失敗したバックエンドを外しておく秒数
+	eject_duration:u64,
+	sticky_streaming:bool,
+}
+#[derive(Clone,Debug,Serialize,Deserialize)]
+pub struct BackendConfig{
+	name:String,
+	url:String,
+}
+#[derive(Clone, Copy,Debug,Serialize,Deserialize)]
+pub enum BalanceMode{
+	RoundRobin,
+	LeastConnections,
+}
+impl Default for UpstreamConfig{
+	fn default() -> Self {
+		Self{
+			backends:vec![],
+			balance:BalanceMode::RoundRobin,
+			health_check_path:"".to_owned(),
+			health_check_interval:5,
+			health_check_timeout:2,
+			eject_duration:30,
+			sticky_streaming:false,
+		}
+	}
+}
+impl UpstreamConfig{
+	pub fn backend_names(&self)->impl Iterator<Item=&str>{
+		self.backends.iter().map(|b|b.name.as_str())
+	}
+	pub fn validate(&self,errors:&mut Vec<String>){
+		let mut names=std::collections::HashSet::new();
+		for b in self.backends.iter(){
+			if !names.insert(b.name.as_str()){
+				errors.push(format!("upstream.backends: duplicate name {:?}",b.name));
+			}
+			match reqwest::Url::parse(&b.url){
+				Ok(url) if url.scheme()=="http"||url.scheme()=="https"=>{},
+				Ok(url)=>errors.push(format!("upstream.backends[{}].url: unsupported scheme {:?}",b.name,url.scheme())),
+				Err(e)=>errors.push(format!("upstream.backends[{}].url: {}",b.name,e)),
+			}
+		}
+		if !self.health_check_path.is_empty()&&!self.health_check_path.starts_with('/'){
+			errors.push("upstream.health_check_path: must start with \"/\"".to_owned());
+		}
+	}
+}
+#[derive(Debug)]
+struct Upstream{
+	name:String,
+	url:String,
+	healthy:AtomicBool,
+	ejected_until:AtomicI64,
+	active:AtomicUsize,
+}
+impl Upstream{
+	fn is_available(&self,now:i64)->bool{
+		self.healthy.load(Ordering::Relaxed)&&self.ejected_until.load(Ordering::Relaxed)<=now
+	}
+}
+/**
+ * 選択したバックエンドへの接続中を表す
+ * dropされるまで接続数に数えられる
+ */
+#[derive(Debug)]
+pub struct UpstreamGuard{
+	upstream:Arc<Upstream>,
+	eject_duration:i64,
+}
+impl UpstreamGuard{
+	pub fn url(&self)->&str{
+		&self.upstream.url
+	}
+	pub fn name(&self)->&str{
+		&self.upstream.name
+	}
+	/**
+	 * 接続に失敗したバックエンドを一定時間振り分け対象から外す
+	 */
+	pub fn mark_failed(&self){
+		tracing::warn!("eject backend {}",self.upstream.url);
+		let until=chrono::Utc::now().timestamp_millis()+self.eject_duration;
+		self.upstream.ejected_until.store(until,Ordering::Relaxed);
+	}
+}
+impl Drop for UpstreamGuard{
+	fn drop(&mut self) {
+		self.upstream.active.fetch_sub(1,Ordering::Relaxed);
+	}
+}
+#[derive(Clone,Debug)]
+pub struct UpstreamService{
+	upstreams:Arc<Vec<Arc<Upstream>>>,
+	config:Arc<UpstreamConfig>,
+	next:Arc<AtomicUsize>,
+}
+impl UpstreamService{
+	pub fn new(config:&ConfigFile)->Self{
+		let upstream_config=config.upstream.clone().unwrap_or_default();
+		let mut backends=upstream_config.backends.clone();
+		if backends.is_empty(){
+			backends.push(BackendConfig{
+				name:"default".to_owned(),
+				url:config.backend.clone(),
+			});
+		}
+		let upstreams=backends.into_iter().map(|b|Arc::new(Upstream{
+			name:b.name,
+			url:b.url.trim_end_matches('/').to_owned(),
+			healthy:AtomicBool::new(true),
+			ejected_until:AtomicI64::new(0),
+			active:AtomicUsize::new(0),
+		})).collect();
+		Self{
+			upstreams:Arc::new(upstreams),
+			config:Arc::new(upstream_config),
+			next:Arc::new(AtomicUsize::new(0)),
+		}
+	}
+	pub fn len(&self)->usize{
+		self.upstreams.len()
+	}
+	pub fn spawn_health_check(&self,client:reqwest::Client){
+		if self.config.health_check_path.is_empty(){
+			return;
+		}
+		//設定の再読み込みで作り直された場合は古い方の確認を止める
+		let weak_upstreams=Arc::downgrade(&self.upstreams);
+		let config=self.config.clone();
+		tokio::spawn(async move{
+			let interval=std::time::Duration::from_secs(config.health_check_interval.max(1));
+			let timeout=std::time::Duration::from_secs(config.health_check_timeout.max(1));
+			loop{
+				let upstreams=match weak_upstreams.upgrade(){
+					Some(upstreams)=>upstreams,
+					None=>break,
+				};
+				let checks=upstreams.iter().map(|upstream|{
+					let client=client.clone();
+					let url=format!("{}{}",upstream.url,config.health_check_path);
+					async move{
+						let healthy=match client.get(url).timeout(timeout).send().await{
+							Ok(res)=>res.status().as_u16()<500,
+							Err(_)=>false,
+						};
+						if upstream.healthy.swap(healthy,Ordering::Relaxed)!=healthy{
+							tracing::info!("backend {} healthy={}",upstream.url,healthy);
+						}
+					}
+				});
+				futures::future::join_all(checks).await;
+				drop(upstreams);
+				tokio::time::sleep(interval).await;
+			}
+		});
+	}
+	fn guard(&self,upstream:&Arc<Upstream>)->UpstreamGuard{
+		upstream.active.fetch_add(1,Ordering::Relaxed);
+		UpstreamGuard{
+			upstream:upstream.clone(),
+			eject_duration:self.config.eject_duration as i64*1000,
+		}
+	}
+	/**
+	 * 振り分け可能なバックエンドの一覧
+	 * 全て停止している場合は全体停止を避けるために全てを候補にする
+	 */
+	fn candidates(&self)->Vec<&Arc<Upstream>>{
+		let now=chrono::Utc::now().timestamp_millis();
+		let available:Vec<_>=self.upstreams.iter().filter(|u|u.is_available(now)).collect();
+		if available.is_empty(){
+			self.upstreams.iter().collect()
+		}else{
+			available
+		}
+	}
+	pub fn pick(&self)->Option<UpstreamGuard>{
+		let candidates=self.candidates();
+		let upstream=match self.config.balance{
+			BalanceMode::RoundRobin=>{
+				let n=self.next.fetch_add(1,Ordering::Relaxed);
+				candidates.get(n%candidates.len().max(1)).copied()
+			},
+			BalanceMode::LeastConnections=>{
+				candidates.into_iter().min_by_key(|u|u.active.load(Ordering::Relaxed))
+			},
+		}?;
+		Some(self.guard(upstream))
+	}
+	/**
+	 * 各バックエンドが応答するか確認する処理を名前と組にして返す
+	 * 振り分けや接続数には影響しない
+	 */
+	pub fn probes(&self,client:&reqwest::Client)->Vec<(String,impl std::future::Future<Output=Result<(),String>>)>{
+		let path=if self.config.health_check_path.is_empty(){
+			"/healthz"
+		}else{
+			self.config.health_check_path.as_str()
+		};
+		self.upstreams.iter().map(|upstream|{
+			let request=client.get(format!("{}{}",upstream.url,path));
+			(upstream.name.clone(),async move{
+				let res=request.send().await.map_err(|e|e.to_string())?;
+				if res.status().is_server_error(){
+					return Err(format!("status {}",res.status()));
+				}
+				Ok(())
+			})
+		}).collect()
+	}
+	/**
+	 * 名前を指定して選ぶ場合は停止中でも候補から外さない
+	 */
+	pub fn pick_named(&self,name:&str)->Option<UpstreamGuard>{
+		let upstream=self.upstreams.iter().find(|u|u.name==name)?;
+		Some(self.guard(upstream))
+	}
+	/**
+	 * 同じキーは同じバックエンドに振り分ける(rendezvous hashing)
+	 * sticky_streamingが無効なら通常の振り分けになる
+	 */
+	pub fn pick_sticky(&self,key:&str)->Option<UpstreamGuard>{
+		if !self.config.sticky_streaming{
+			return self.pick();
+		}
+		let upstream=self.candidates().into_iter().max_by_key(|u|{
+			let mut hasher=DefaultHasher::new();
+			key.hash(&mut hasher);
+			u.name.hash(&mut hasher);
+			hasher.finish()
+		})?;
+		Some(self.guard(upstream))
+	}
+}
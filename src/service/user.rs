@@ -2,7 +2,7 @@ use std::collections::HashMap;
 
 use redis::{aio::MultiplexedConnection, AsyncCommands};
 
-use crate::{models::{following::MiFollowing, user::MiUser, user_memo::MiUserMemo, user_note_pining::MiUserNotePining, user_profile::MiUserProfile}, DBConnection, DataBase};
+use crate::{models::{access_token::MiAccessToken, following::MiFollowing, user::MiUser, user_memo::MiUserMemo, user_note_pining::MiUserNotePining, user_profile::MiUserProfile}, DBConnection, DataBase};
 
 use super::{announcement::AnnouncementService, id_service::IdService, role::RoleService};
 
@@ -65,6 +65,16 @@ impl UserService{
 			announcement_service,
 		}
 	}
+	/**
+	 * APIトークン(アクセストークンまたはユーザーのネイティブトークン)からユーザーを取得する
+	 */
+	pub async fn authenticate(&self,token:&str)->Option<MiUser>{
+		let mut con=self.db.get().await?;
+		match MiAccessToken::load_by_id(&mut con,token).await{
+			Some(token)=>MiUser::load_by_id(&mut con,&token.user_id).await,
+			None=>MiUser::load_by_token(&mut con,token).await,
+		}
+	}
 	pub async fn pack(&self,user:&MiUser,me_id:Option<&str>,opts:&UserPackOptions)->Option<serde_json::Value>{
 		let is_detailed = opts.schema != UserPackSchema::UserLite;
 		let is_me = me_id.map(|id|id==user.id).unwrap_or(false);
@@ -166,7 +176,7 @@ impl UserService{
 				use crate::models::following::following::dsl::following;
 				use crate::models::following::following::dsl::*;
 				following.filter(followerId.eq(me_id)).filter(followeeId.eq(target)).select(MiFollowing::as_select()).first(&mut con).await.map_err(|e|{
-					eprintln!("{:?}",e);
+					tracing::error!("{:?}",e);
 				})
 			}.ok();
 			res
@@ -176,7 +186,7 @@ impl UserService{
 			use crate::models::following::following::dsl::following;
 			use crate::models::following::following::dsl::*;
 			let res:Option<crate::models::following::MiFollowing>=following.filter(followerId.eq(target)).filter(followeeId.eq(me_id)).first(&mut con).await.map_err(|e|{
-				eprintln!("{:?}",e);
+				tracing::error!("{:?}",e);
 			}).ok();
 			res.is_some()
 		};
@@ -185,7 +195,7 @@ impl UserService{
 			use crate::models::follow_request::follow_request::dsl::follow_request;
 			use crate::models::follow_request::follow_request::dsl::*;
 			let res:Option<crate::models::follow_request::MiFollowRequest>=follow_request.filter(followerId.eq(me_id)).filter(followeeId.eq(target)).first(&mut con).await.map_err(|e|{
-				eprintln!("{:?}",e);
+				tracing::error!("{:?}",e);
 			}).ok();
 			res.is_some()
 		};
@@ -194,7 +204,7 @@ impl UserService{
 			use crate::models::follow_request::follow_request::dsl::follow_request;
 			use crate::models::follow_request::follow_request::dsl::*;
 			let res:Option<crate::models::follow_request::MiFollowRequest>=follow_request.filter(followerId.eq(target)).filter(followeeId.eq(me_id)).first(&mut con).await.map_err(|e|{
-				eprintln!("{:?}",e);
+				tracing::error!("{:?}",e);
 			}).ok();
 			res.is_some()
 		};
@@ -203,7 +213,7 @@ impl UserService{
 			use crate::models::blocking::blocking::dsl::blocking;
 			use crate::models::blocking::blocking::dsl::*;
 			let res:Option<crate::models::blocking::MiBlocking>=blocking.filter(blockerId.eq(me_id)).filter(blockeeId.eq(target)).first(&mut con).await.map_err(|e|{
-				eprintln!("{:?}",e);
+				tracing::error!("{:?}",e);
 			}).ok();
 			res.is_some()
 		};
@@ -212,7 +222,7 @@ impl UserService{
 			use crate::models::blocking::blocking::dsl::blocking;
 			use crate::models::blocking::blocking::dsl::*;
 			let res:Option<crate::models::blocking::MiBlocking>=blocking.filter(blockerId.eq(target)).filter(blockeeId.eq(me_id)).first(&mut con).await.map_err(|e|{
-				eprintln!("{:?}",e);
+				tracing::error!("{:?}",e);
 			}).ok();
 			res.is_some()
 		};
@@ -221,7 +231,7 @@ impl UserService{
 			use crate::models::muting::muting::dsl::muting;
 			use crate::models::muting::muting::dsl::*;
 			let res:Option<crate::models::muting::MiMuting>=muting.filter(muterId.eq(me_id)).filter(muteeId.eq(target)).first(&mut con).await.map_err(|e|{
-				eprintln!("{:?}",e);
+				tracing::error!("{:?}",e);
 			}).ok();
 			res.is_some()
 		};
@@ -230,7 +240,7 @@ impl UserService{
 			use crate::models::renote_muting::renote_muting::dsl::renote_muting;
 			use crate::models::renote_muting::renote_muting::dsl::*;
 			let res:Option<crate::models::renote_muting::MiRenoteMuting>=renote_muting.filter(muterId.eq(me_id)).filter(muteeId.eq(target)).first(&mut con).await.map_err(|e|{
-				eprintln!("{:?}",e);
+				tracing::error!("{:?}",e);
 			}).ok();
 			res.is_some()
 		};